@@ -0,0 +1,404 @@
+use std::{fs::{self, File}, io::BufReader, path::{Path, PathBuf}, time::SystemTime};
+
+use crate::{backup, dal::DAL, error::CustomError, meta::META_PAGE_NUM};
+
+const FULL_EXTENSION: &str = "full";
+const DIFF_EXTENSION: &str = "diff";
+
+/// The moment `DB::restore_to` rebuilds a database at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Right after the commit with this txid.
+    Txid(u64),
+    /// The last commit at or before this time.
+    Time(SystemTime),
+}
+
+impl From<u64> for RestorePoint {
+    fn from(txid: u64) -> RestorePoint {
+        RestorePoint::Txid(txid)
+    }
+}
+
+impl From<SystemTime> for RestorePoint {
+    fn from(time: SystemTime) -> RestorePoint {
+        RestorePoint::Time(time)
+    }
+}
+
+/// A file in the archive directory, named after the txid it ends at. A full one holds every page of the database,
+/// a diff one the pages written by that single commit, in the format of `DB::backup_incremental`.
+#[derive(Debug, Clone)]
+struct ArchiveFile {
+    txid: u64,
+    full: bool,
+    path: PathBuf,
+}
+
+/// Keeps a copy of every commit in `Options::archive_dir`: a full copy of the database when archiving starts, and
+/// after that the pages each commit wrote. Full copies are taken again whenever a diff cannot follow the one
+/// before it, for example after `DB::compact` or when writing a diff failed.
+#[derive(Debug)]
+pub(crate) struct Archive {
+    dir: PathBuf,
+    /// The txid of the last file written, unless the next commit needs a full copy.
+    last_txid: Option<u64>,
+}
+
+impl Archive {
+
+    /// Opens the archive at `dir`, creating it when needed, and takes a full copy of `dal` unless the archive
+    /// already ends at its txid. Fails when the archive holds txids past the database, which happens when a
+    /// database restored to an earlier txid is opened with the archive it was restored from.
+    pub(crate) fn open(dir: &Path, dal: &DAL) -> Result<Archive, CustomError> {
+        match fs::create_dir_all(dir) {
+            Ok(()) => {},
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        }
+        let txid = match dal.meta {
+            Some(ref meta) => meta.txid,
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()));
+            }
+        };
+
+        let mut archive = Archive {
+            dir: dir.to_path_buf(),
+            last_txid: None,
+        };
+        match list(dir) {
+            Ok(files) => match files.last() {
+                Some(last) if last.txid > txid => {
+                    Err(CustomError::new(format!("Archive {} holds txids up to {}, past the database at txid {}", dir.display(), last.txid, txid)))
+                }
+                Some(last) if last.txid == txid => {
+                    archive.last_txid = Some(txid);
+                    Ok(archive)
+                }
+                _ => match archive.snapshot(dal) {
+                    Ok(()) => Ok(archive),
+                    Err(error) => Err(error)
+                }
+            }
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Writes a full copy of `dal` at its current txid.
+    pub(crate) fn snapshot(&mut self, dal: &DAL) -> Result<(), CustomError> {
+        let max_page = match dal.freelist {
+            Some(ref freelist) => freelist.max_page,
+            None => {
+                return Err(CustomError::new("Freelist not initialized".to_string()));
+            }
+        };
+        let pages: Vec<u64> = (0..=max_page).collect();
+        self.write(dal, 0, &pages, FULL_EXTENSION)
+    }
+
+    /// Writes the pages written by the commit that just brought `dal` to its txid, or a full copy when the last
+    /// file written is not from the commit before.
+    pub(crate) fn record(&mut self, dal: &DAL, page_ids: &[u64]) -> Result<(), CustomError> {
+        let (txid, freelist_page) = match dal.meta {
+            Some(ref meta) => (meta.txid, meta.freelist_page),
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()));
+            }
+        };
+        if self.last_txid != Some(txid - 1) {
+            return self.snapshot(dal);
+        }
+
+        let mut pages = page_ids.to_vec();
+        pages.push(META_PAGE_NUM);
+        pages.push(freelist_page);
+        if let Some(ref freelist) = dal.freelist {
+            pages.extend(freelist.overflow_pages.iter());
+        }
+        pages.sort();
+        pages.dedup();
+        self.write(dal, txid - 1, &pages, DIFF_EXTENSION)
+    }
+
+    /// Writes the file next to its final name and renames it into place, so the archive only ever holds complete
+    /// files.
+    fn write(&mut self, dal: &DAL, since_txid: u64, pages: &[u64], extension: &str) -> Result<(), CustomError> {
+        let txid = match dal.meta {
+            Some(ref meta) => meta.txid,
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()));
+            }
+        };
+        self.last_txid = None;
+
+        let path = self.dir.join(format!("{:020}.{}", txid, extension));
+        let partial_path = self.dir.join(format!("{:020}.{}.partial", txid, extension));
+        let mut file = match File::create(&partial_path) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+        let written = match backup::write_pages(dal, since_txid, pages, &mut file) {
+            Ok(_) => match file.sync_all().and_then(|()| fs::rename(&partial_path, &path)) {
+                Ok(()) => Ok(()),
+                Err(error) => Err(CustomError::new(error.to_string()))
+            }
+            Err(error) => Err(error)
+        };
+        if written.is_err() {
+            let _ = fs::remove_file(&partial_path);
+            return written;
+        }
+
+        self.last_txid = Some(txid);
+        Ok(())
+    }
+}
+
+/// The complete files in the archive at `dir`, in txid order with a full copy before a diff of the same txid.
+fn list(dir: &Path) -> Result<Vec<ArchiveFile>, CustomError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            return Err(CustomError::new(error.to_string()));
+        }
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+        let full = match path.extension().and_then(|extension| extension.to_str()) {
+            Some(FULL_EXTENSION) => true,
+            Some(DIFF_EXTENSION) => false,
+            _ => continue
+        };
+        if let Some(Ok(txid)) = path.file_stem().and_then(|stem| stem.to_str()).map(|stem| stem.parse::<u64>()) {
+            files.push(ArchiveFile {
+                txid,
+                full,
+                path,
+            });
+        }
+    }
+
+    files.sort_by_key(|file| (file.txid, !file.full));
+    Ok(files)
+}
+
+/// Rebuilds the database as it was at `point` into a new file at `path` from the archive at `dir`, starting from
+/// the last full copy at or before it. Returns the txid the database is restored to.
+pub(crate) fn restore_to(dir: &Path, point: RestorePoint, path: &Path) -> Result<u64, CustomError> {
+    let files = match list(dir) {
+        Ok(files) => files,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    let txid = match point {
+        RestorePoint::Txid(txid) => txid,
+        RestorePoint::Time(time) => {
+            let mut found = None;
+            for file in &files {
+                let report = match File::open(&file.path) {
+                    Ok(mut reader) => backup::read_report(&mut reader),
+                    Err(error) => Err(CustomError::new(error.to_string()))
+                };
+                match report {
+                    Ok(report) if report.timestamp <= time => {
+                        found = Some(report.txid);
+                    }
+                    Ok(_) => {},
+                    Err(error) => {
+                        return Err(error);
+                    }
+                }
+            }
+            match found {
+                Some(txid) => txid,
+                None => {
+                    return Err(CustomError::new("The archive holds nothing from before that time".to_string()));
+                }
+            }
+        }
+    };
+
+    let start = match files.iter().rposition(|file| file.full && file.txid <= txid) {
+        Some(start) => start,
+        None => {
+            return Err(CustomError::new(format!("The archive holds no full copy at or before txid {}", txid)));
+        }
+    };
+    let full = &files[start];
+    let mut chain = vec![full];
+    chain.extend(files[start..].iter().filter(|file| !file.full && file.txid > full.txid && file.txid <= txid));
+    match chain.last() {
+        Some(last) if last.txid == txid => {},
+        _ => {
+            return Err(CustomError::new(format!("The archive does not reach txid {}", txid)));
+        }
+    }
+
+    let mut backups = vec![];
+    for file in chain {
+        match File::open(&file.path) {
+            Ok(reader) => backups.push(BufReader::new(reader)),
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        }
+    }
+    backup::restore(path, backups)
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod tests {
+    use std::{fs, path::Path, thread, time::{Duration, SystemTime}};
+
+    use crate::{dal::{Options, DEFAULT_OPTIONS}, db::DB, test_util::{new_options, open_existing, remove}, tx::ReadTx};
+
+    use super::RestorePoint;
+
+    /// Every item of `users`.
+    fn users(db: &DB) -> Vec<(String, Vec<u8>)> {
+        db.view(|tx| {
+            let users = tx.get_collection("users".to_string())?.unwrap();
+            users.iter(tx).map(|item| item.map(|item| (item.key, item.value))).collect()
+        }).unwrap()
+    }
+
+    #[test]
+    fn restore_to_txid_and_time() {
+        let archive_dir = "./db_archive_test_internal_dir_1";
+        if Path::new(archive_dir).exists() {
+            match fs::remove_dir_all(Path::new(archive_dir)) {
+                Ok(()) => {},
+                Err(_) => {
+                    assert!(false, "Failed to clean up archive directory");
+                }
+            }
+        }
+        let options = Options {
+            archive_dir: Some(archive_dir),
+            ..new_options("./db_archive_test_internal_1")
+        };
+        for path in ["./db_archive_test_internal_2", "./db_archive_test_internal_3", "./db_archive_test_internal_4", "./db_archive_test_internal_5"] {
+            remove(path);
+        }
+
+        let db = DB::open(options.clone()).unwrap();
+        let before = SystemTime::now();
+        thread::sleep(Duration::from_millis(10));
+        db.update(|tx| {
+            let mut users = tx.create_collection("users".to_string())?;
+            for i in 0..300 {
+                users.put(format!("user_{:04}", i), format!("value_{}", i).as_bytes().to_owned(), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        let good = users(&db);
+        thread::sleep(Duration::from_millis(10));
+        let deploy = SystemTime::now();
+        thread::sleep(Duration::from_millis(10));
+
+        // A buggy deploy overwrites every value, then deletes some of them.
+        db.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            for i in 0..300 {
+                users.put(format!("user_{:04}", i), "bad".as_bytes().to_owned(), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        db.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            for i in 0..100 {
+                users.remove(format!("user_{:04}", i), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(DB::restore_to(archive_dir, 1, "./db_archive_test_internal_2").unwrap(), 1);
+        assert_eq!(users(&open_existing("./db_archive_test_internal_2")), good);
+        assert_eq!(DB::restore_to(archive_dir, deploy, "./db_archive_test_internal_3").unwrap(), 1);
+        assert_eq!(users(&open_existing("./db_archive_test_internal_3")), good);
+        assert_eq!(DB::restore_to(archive_dir, RestorePoint::Txid(3), "./db_archive_test_internal_4").unwrap(), 3);
+        let restored = open_existing("./db_archive_test_internal_4");
+        assert_eq!(users(&restored), users(&db));
+        assert!(restored.check().unwrap().is_ok());
+
+        assert!(DB::restore_to(archive_dir, 4, "./db_archive_test_internal_5").is_err());
+        assert!(DB::restore_to(archive_dir, before - Duration::from_secs(60), "./db_archive_test_internal_5").is_err());
+        assert!(!Path::new("./db_archive_test_internal_5").exists());
+
+        // The archive holds commits past a database restored to an earlier txid.
+        drop(db);
+        assert!(DB::open(Options {
+            path: "./db_archive_test_internal_2",
+            archive_dir: Some(archive_dir),
+            ..DEFAULT_OPTIONS
+        }).is_err());
+
+        // Reopening carries on with diffs, and compaction starts over with a full copy.
+        let db = DB::open(options).unwrap();
+        db.compact().unwrap();
+        db.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            users.put("user_0000".to_string(), "fixed".as_bytes().to_owned(), tx)
+        }).unwrap();
+        let files: Vec<String> = fs::read_dir(archive_dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        assert_eq!(files.iter().filter(|name| name.ends_with(".full")).count(), 2);
+        assert_eq!(files.iter().filter(|name| name.ends_with(".diff")).count(), 4);
+
+        assert_eq!(DB::restore_to(archive_dir, 4, "./db_archive_test_internal_5").unwrap(), 4);
+        let restored = open_existing("./db_archive_test_internal_5");
+        assert_eq!(users(&restored), users(&db));
+        assert!(restored.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn failed_archiving_keeps_the_commit() {
+        let archive_dir = "./db_archive_test_internal_dir_2";
+        if Path::new(archive_dir).exists() {
+            match fs::remove_dir_all(Path::new(archive_dir)) {
+                Ok(()) => {},
+                Err(_) => {
+                    assert!(false, "Failed to clean up archive directory");
+                }
+            }
+        }
+        let options = Options {
+            archive_dir: Some(archive_dir),
+            ..new_options("./db_archive_test_internal_6")
+        };
+        remove("./db_archive_test_internal_7");
+
+        let db = DB::open(options).unwrap();
+        db.update(|tx| tx.create_collection("users".to_string()).map(|_| ())).unwrap();
+
+        // A file in place of the directory makes archiving fail.
+        fs::remove_dir_all(archive_dir).unwrap();
+        fs::write(archive_dir, b"").unwrap();
+        db.update(|tx| tx.get_collection("users".to_string())?.unwrap().put("user_1".to_string(), vec![1], tx)).unwrap();
+        assert_eq!(db.metrics().after_commit_errors, 1);
+        assert_eq!(users(&db), vec![("user_1".to_string(), vec![1])]);
+
+        // Once the directory is back, the next commit is archived as a full copy.
+        fs::remove_file(archive_dir).unwrap();
+        fs::create_dir(archive_dir).unwrap();
+        db.update(|tx| tx.get_collection("users".to_string())?.unwrap().put("user_2".to_string(), vec![2], tx)).unwrap();
+        assert_eq!(db.metrics().after_commit_errors, 1);
+        assert!(Path::new(archive_dir).join(format!("{:020}.full", 3)).exists());
+
+        assert_eq!(DB::restore_to(archive_dir, 3, "./db_archive_test_internal_7").unwrap(), 3);
+        assert_eq!(users(&open_existing("./db_archive_test_internal_7")), users(&db));
+    }
+}
@@ -0,0 +1,434 @@
+use std::{collections::VecDeque, future::Future, panic::{self, AssertUnwindSafe}, pin::Pin, sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError}, task::{Context, Poll, Waker}, thread};
+
+use crate::{dal::Options, db::DB, error::CustomError, tx::{Tx, TxMut}};
+
+pub struct AsyncOptions {
+    /// Number of threads running transactions and other blocking work.
+    pub threads: usize,
+    /// Number of jobs that may wait for a free thread. Callers past this limit wait until a job is picked up.
+    pub max_pending: usize,
+}
+
+pub const DEFAULT_ASYNC_OPTIONS: AsyncOptions = AsyncOptions {
+    threads: 4,
+    max_pending: 64,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct PoolState {
+    jobs: VecDeque<Job>,
+    closed: bool,
+    /// Futures waiting for room in `jobs`.
+    waiting: Vec<Waker>,
+}
+
+/// A fixed set of threads running blocking jobs, fed by a bounded queue.
+struct Pool {
+    state: Mutex<PoolState>,
+    job_ready: Condvar,
+    max_pending: usize,
+}
+
+/// Closes the pool once the last `AsyncDB` using it is dropped. Queued jobs still run before the threads exit.
+struct PoolHandle(Arc<Pool>);
+
+impl Pool {
+
+    fn start(options: &AsyncOptions) -> Result<Arc<Pool>, CustomError> {
+        if options.threads == 0 || options.max_pending == 0 {
+            return Err(CustomError::new("Async options need at least one thread and one pending job".to_string()));
+        }
+
+        let pool = Arc::new(Pool {
+            state: Mutex::new(PoolState {
+                jobs: VecDeque::new(),
+                closed: false,
+                waiting: Vec::new(),
+            }),
+            job_ready: Condvar::new(),
+            max_pending: options.max_pending,
+        });
+
+        for i in 0..options.threads {
+            let worker = Arc::clone(&pool);
+            let spawned = thread::Builder::new()
+                .name(format!("libradb-blocking-{}", i))
+                .spawn(move || worker.work());
+            if let Err(error) = spawned {
+                pool.close();
+                return Err(CustomError::new(format!("Failed to start blocking thread: {}", error)));
+            }
+        }
+
+        Ok(pool)
+    }
+
+    /// Queues `job`, or hands it back and registers `waker` to be woken once there is room.
+    fn submit(&self, job: Job, waker: &Waker) -> Result<Option<Job>, CustomError> {
+        let mut state = self.lock_state();
+        if state.closed {
+            return Err(CustomError::new("Database is closed".to_string()));
+        }
+
+        if state.jobs.len() >= self.max_pending {
+            state.waiting.push(waker.clone());
+            return Ok(Some(job));
+        }
+
+        state.jobs.push_back(job);
+        self.job_ready.notify_one();
+        Ok(None)
+    }
+
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut state = self.lock_state();
+                while state.jobs.is_empty() && !state.closed {
+                    state = self.job_ready.wait(state).unwrap_or_else(PoisonError::into_inner);
+                }
+
+                match state.jobs.pop_front() {
+                    Some(job) => {
+                        for waker in state.waiting.drain(..) {
+                            waker.wake();
+                        }
+                        job
+                    }
+                    None => {
+                        return;
+                    }
+                }
+            };
+
+            job();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.lock_state();
+        state.closed = true;
+        for waker in state.waiting.drain(..) {
+            waker.wake();
+        }
+        self.job_ready.notify_all();
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for PoolHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+struct SlotState<T> {
+    result: Option<Result<T, CustomError>>,
+    waker: Option<Waker>,
+    cancelled: bool,
+}
+
+/// Shared between a `BlockingFuture` and its job, carries the result back and the cancellation forward.
+struct Slot<T> {
+    state: Mutex<SlotState<T>>,
+}
+
+impl <T> Slot<T> {
+
+    fn lock_state(&self) -> MutexGuard<'_, SlotState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.lock_state().cancelled
+    }
+
+    fn complete(&self, result: Result<T, CustomError>) {
+        let mut state = self.lock_state();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves to the result of a job run on the blocking thread pool.
+///
+/// Dropping the future cancels the job: a job that has not started yet is skipped, and a transaction
+/// that is running is rolled back instead of committed.
+pub struct BlockingFuture<T> {
+    pool: Arc<Pool>,
+    job: Option<Job>,
+    slot: Arc<Slot<T>>,
+}
+
+impl <T: Send + 'static> BlockingFuture<T> {
+
+    fn new<F>(pool: Arc<Pool>, f: F) -> BlockingFuture<T>
+    where
+        F: FnOnce(&Slot<T>) -> Result<T, CustomError> + Send + 'static,
+    {
+        let slot = Arc::new(Slot {
+            state: Mutex::new(SlotState {
+                result: None,
+                waker: None,
+                cancelled: false,
+            }),
+        });
+
+        let job_slot = Arc::clone(&slot);
+        let job: Job = Box::new(move || {
+            if job_slot.is_cancelled() {
+                return;
+            }
+
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| f(&job_slot))) {
+                Ok(result) => result,
+                Err(_) => Err(CustomError::new("Blocking job panicked".to_string()))
+            };
+            job_slot.complete(result);
+        });
+
+        BlockingFuture {
+            pool,
+            job: Some(job),
+            slot,
+        }
+    }
+}
+
+impl <T> Future for BlockingFuture<T> {
+    type Output = Result<T, CustomError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Some(job) = this.job.take() {
+            match this.pool.submit(job, cx.waker()) {
+                Ok(None) => {}
+                Ok(Some(job)) => {
+                    this.job = Some(job);
+                    return Poll::Pending;
+                }
+                Err(error) => {
+                    return Poll::Ready(Err(error));
+                }
+            }
+        }
+
+        let mut state = this.slot.lock_state();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl <T> Drop for BlockingFuture<T> {
+    fn drop(&mut self) {
+        self.slot.lock_state().cancelled = true;
+    }
+}
+
+/// A handle on a `DB` for async code. Transactions run on a dedicated pool of blocking threads so that file I/O
+/// never stalls the executor. Cloning the handle shares the database and the pool.
+#[derive(Clone)]
+pub struct AsyncDB {
+    db: Arc<DB>,
+    pool: Arc<PoolHandle>,
+}
+
+impl AsyncDB {
+
+    /// Opens the database on the blocking thread pool.
+    pub async fn open(options: Options, async_options: AsyncOptions) -> Result<AsyncDB, CustomError> {
+        let pool = match Pool::start(&async_options) {
+            Ok(pool) => PoolHandle(pool),
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        match BlockingFuture::new(Arc::clone(&pool.0), move |_| DB::open(options)).await {
+            Ok(db) => Ok(AsyncDB {
+                db: Arc::new(db),
+                pool: Arc::new(pool),
+            }),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Wraps a database that is already open.
+    pub fn new(db: Arc<DB>, async_options: AsyncOptions) -> Result<AsyncDB, CustomError> {
+        match Pool::start(&async_options) {
+            Ok(pool) => Ok(AsyncDB {
+                db,
+                pool: Arc::new(PoolHandle(pool)),
+            }),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// The underlying database, for work that is fine to run on the current thread.
+    pub fn db(&self) -> &Arc<DB> {
+        &self.db
+    }
+
+    /// Runs `f` inside a read-write transaction on the blocking thread pool, see `DB::update`.
+    /// A panic in `f` rolls the transaction back and resolves to an error.
+    pub fn update<T, F>(&self, f: F) -> BlockingFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TxMut) -> Result<T, CustomError> + Send + 'static,
+    {
+        let db = Arc::clone(&self.db);
+        BlockingFuture::new(Arc::clone(&self.pool.0), move |slot| {
+            db.update(|tx| {
+                let value = match f(tx) {
+                    Ok(value) => value,
+                    Err(error) => {
+                        return Err(error);
+                    }
+                };
+
+                // The caller is gone, so nothing would see the transaction succeed.
+                if slot.is_cancelled() {
+                    return Err(CustomError::new("Transaction cancelled".to_string()));
+                }
+                Ok(value)
+            })
+        })
+    }
+
+    /// Runs `f` inside a read-only transaction on the blocking thread pool, see `DB::view`.
+    pub fn view<T, F>(&self, f: F) -> BlockingFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Tx) -> Result<T, CustomError> + Send + 'static,
+    {
+        let db = Arc::clone(&self.db);
+        BlockingFuture::new(Arc::clone(&self.pool.0), move |_| db.view(f))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{future::{poll_fn, Future}, pin::Pin, sync::mpsc, task::Poll, time::Duration};
+
+    use crate::{test_util::new_options, tx::ReadTx};
+
+    use super::{AsyncDB, AsyncOptions, DEFAULT_ASYNC_OPTIONS};
+
+    /// Polls `future` once with the waker of the running task, without waiting for it.
+    async fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx))).await
+    }
+
+    async fn open(path: &'static str, async_options: AsyncOptions) -> AsyncDB {
+        AsyncDB::open(new_options(path), async_options).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn update_and_view() {
+        let db = open("./db_async_db_test_internal_1", DEFAULT_ASYNC_OPTIONS).await;
+
+        db.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            collection.put("key1".to_string(), "value1".as_bytes().to_owned(), tx)
+        }).await.unwrap();
+
+        let value = db.view(|tx| {
+            let collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            Ok(collection.find("key1".to_string(), tx)?.map(|item| item.value))
+        }).await.unwrap();
+        assert_eq!(value, Some("value1".as_bytes().to_owned()));
+
+        let result = db.update(|tx| -> Result<(), _> {
+            tx.create_collection("panicking_collection".to_string())?;
+            panic!("abort");
+        }).await;
+        assert!(result.is_err());
+        let found = db.view(|tx| tx.get_collection("panicking_collection".to_string())).await.unwrap();
+        assert!(found.is_none());
+    }
+
+    #[tokio::test]
+    async fn pending_jobs_are_bounded() {
+        let db = open("./db_async_db_test_internal_2", AsyncOptions {
+            threads: 1,
+            max_pending: 1,
+        }).await;
+
+        let (started_sender, started) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel::<()>();
+        let mut running = db.view(move |_| {
+            started_sender.send(()).unwrap();
+            release_receiver.recv().unwrap();
+            Ok(1)
+        });
+        assert!(poll_once(&mut running).await.is_pending());
+        started.recv().unwrap();
+
+        let mut queued = db.view(|_| Ok(2));
+        assert!(poll_once(&mut queued).await.is_pending());
+
+        // The only thread is busy and the queue is full, so this job is held back by the future.
+        let mut held_back = db.view(|_| Ok(3));
+        assert!(poll_once(&mut held_back).await.is_pending());
+        assert!(held_back.job.is_some());
+
+        release.send(()).unwrap();
+        assert_eq!(running.await.unwrap(), 1);
+        assert_eq!(queued.await.unwrap(), 2);
+        assert_eq!(held_back.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn dropped_futures_are_cancelled() {
+        let db = open("./db_async_db_test_internal_3", AsyncOptions {
+            threads: 1,
+            max_pending: 4,
+        }).await;
+
+        let (started_sender, started) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel::<()>();
+        let mut running = db.update(move |tx| {
+            tx.create_collection("running_collection".to_string())?;
+            started_sender.send(()).unwrap();
+            release_receiver.recv().unwrap();
+            Ok(())
+        });
+        assert!(poll_once(&mut running).await.is_pending());
+        started.recv().unwrap();
+
+        let (ran_sender, ran) = mpsc::channel();
+        let mut queued = db.update(move |tx| {
+            ran_sender.send(()).unwrap();
+            tx.create_collection("queued_collection".to_string()).map(|_| ())
+        });
+        assert!(poll_once(&mut queued).await.is_pending());
+
+        drop(queued);
+        drop(running);
+        release.send(()).unwrap();
+
+        // The pool has a single thread, so this runs after both cancelled jobs are done with.
+        let (running_found, queued_found) = db.view(|tx| {
+            Ok((
+                tx.get_collection("running_collection".to_string())?.is_some(),
+                tx.get_collection("queued_collection".to_string())?.is_some(),
+            ))
+        }).await.unwrap();
+        assert!(!running_found);
+        assert!(!queued_found);
+        assert!(ran.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+}
@@ -0,0 +1,719 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{collection::Collection, compact::sync_directory, consts::{PAGE_ID_SIZE, PAGE_SIZE}, dal::DAL, error::CustomError, freelist::Freelist, meta::{Meta, META_PAGE_NUM}, tx::ReadTx};
+
+/// Page of the freelist in a backup, right after the meta page. The tree pages follow it.
+const BACKUP_FREELIST_PAGE: u64 = META_PAGE_NUM + 1;
+
+/// Starts every file written by `DB::backup_incremental`.
+const BACKUP_MAGIC: &[u8; 8] = b"LIBRABAK";
+
+/// Magic, page size, since txid, txid, timestamp, max page and page count.
+const BACKUP_HEADER_SIZE: usize = BACKUP_MAGIC.len() + 6 * PAGE_ID_SIZE;
+
+/// Describes a backup written by `DB::backup_incremental`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupReport {
+    /// The txid the backup holds the changes since, 0 for a full backup.
+    pub since_txid: u64,
+    /// The txid of the last commit in the backup. Pass it as `since_txid` to take the next one in the chain.
+    pub txid: u64,
+    pub timestamp: SystemTime,
+    /// Number of pages in the backup.
+    pub pages: u64,
+    pub bytes: u64,
+}
+
+struct BackupHeader {
+    page_size: u64,
+    since_txid: u64,
+    txid: u64,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    max_page: u64,
+    page_count: u64,
+}
+
+impl BackupHeader {
+
+    fn serialize(&self) -> [u8; BACKUP_HEADER_SIZE] {
+        let mut buf = [0u8; BACKUP_HEADER_SIZE];
+        buf[..BACKUP_MAGIC.len()].clone_from_slice(BACKUP_MAGIC);
+
+        let mut pos = BACKUP_MAGIC.len();
+        for value in [self.page_size, self.since_txid, self.txid, self.timestamp, self.max_page, self.page_count] {
+            buf[pos..pos+PAGE_ID_SIZE].clone_from_slice(&value.to_le_bytes());
+            pos += PAGE_ID_SIZE;
+        }
+
+        buf
+    }
+
+    fn deserialize(buf: [u8; BACKUP_HEADER_SIZE]) -> Result<BackupHeader, CustomError> {
+        if buf[..BACKUP_MAGIC.len()] != BACKUP_MAGIC[..] {
+            return Err(CustomError::new("Not a backup written by backup_incremental".to_string()));
+        }
+
+        let mut values = [0u64; 6];
+        let mut pos = BACKUP_MAGIC.len();
+        for value in values.iter_mut() {
+            let mut u64_bytes = [0u8; PAGE_ID_SIZE];
+            u64_bytes.clone_from_slice(&buf[pos..pos+PAGE_ID_SIZE]);
+            *value = u64::from_le_bytes(u64_bytes);
+            pos += PAGE_ID_SIZE;
+        }
+
+        Ok(BackupHeader {
+            page_size: values[0],
+            since_txid: values[1],
+            txid: values[2],
+            timestamp: values[3],
+            max_page: values[4],
+            page_count: values[5],
+        })
+    }
+}
+
+/// Txid of the last commit that wrote each page of a database, kept in a file next to it named `<path>-txids`.
+/// The file starts with the base txid, the first one whose changes it records, followed by one txid per page id.
+/// Pages not written since the file was started read 0.
+#[derive(Debug)]
+pub(crate) struct PageTxids {
+    file: File,
+    base_txid: u64,
+}
+
+impl PageTxids {
+
+    fn path(db_path: &Path) -> PathBuf {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push("-txids");
+        PathBuf::from(path)
+    }
+
+    /// Opens the page txids of the database at `db_path`, whose last commit is `txid`. They are started over
+    /// when `fresh` is set, or when they are missing or were not written for this file.
+    pub(crate) fn open(db_path: &Path, txid: u64, fresh: bool) -> Result<PageTxids, CustomError> {
+        let mut file = match File::options().read(true).write(true).create(true).truncate(false).open(PageTxids::path(db_path)) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+
+        let mut u64_bytes = [0u8; PAGE_ID_SIZE];
+        let base_txid = match file.read_exact(&mut u64_bytes) {
+            Ok(()) => u64::from_le_bytes(u64_bytes),
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => u64::MAX,
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+        if !fresh && base_txid <= txid + 1 {
+            return Ok(PageTxids {
+                file,
+                base_txid,
+            });
+        }
+
+        // Changes up to `txid` are unknown, so a backup since any of them has to be a full one.
+        let base_txid = txid + 1;
+        let started = file.set_len(0)
+            .and_then(|()| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(&base_txid.to_le_bytes()));
+        match started {
+            Ok(()) => Ok(PageTxids {
+                file,
+                base_txid,
+            }),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+    }
+
+    /// Removes the page txids of the database at `db_path`, for when the file is replaced by another one.
+    pub(crate) fn remove(db_path: &Path) -> Result<(), CustomError> {
+        match fs::remove_file(PageTxids::path(db_path)) {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+    }
+
+    pub(crate) fn base_txid(&self) -> u64 {
+        self.base_txid
+    }
+
+    /// Records that the commit `txid` wrote `page_ids`. Called before the meta page is written, so after a crash
+    /// a page can only be recorded as changed later than it was, which at worst puts it in a backup it is not
+    /// needed in.
+    pub(crate) fn stamp(&self, page_ids: impl Iterator<Item = u64>, txid: u64) -> Result<(), CustomError> {
+        let mut file = match self.file.try_clone() {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+
+        for page_id in page_ids {
+            let offset = (page_id + 1) * PAGE_ID_SIZE as u64;
+            match file.seek(SeekFrom::Start(offset)).and_then(|_| file.write_all(&txid.to_le_bytes())) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(CustomError::new(error.to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The txid of every page, indexed by page id. Pages past the end were not written since the base txid.
+    pub(crate) fn load(&self) -> Result<Vec<u64>, CustomError> {
+        let mut file = match self.file.try_clone() {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+
+        let mut bytes = vec![];
+        match file.seek(SeekFrom::Start(PAGE_ID_SIZE as u64)).and_then(|_| file.read_to_end(&mut bytes)) {
+            Ok(_) => {},
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        }
+
+        Ok(bytes.chunks_exact(PAGE_ID_SIZE).map(|chunk| {
+            let mut u64_bytes = [0u8; PAGE_ID_SIZE];
+            u64_bytes.clone_from_slice(chunk);
+            u64::from_le_bytes(u64_bytes)
+        }).collect())
+    }
+}
+
+/// Whether the items of a tree are collection entries, whose trees are part of the backup too, or plain values.
+#[derive(Clone, Copy)]
+enum Tree {
+    Directory,
+    Values,
+}
+
+/// Writes the pages reachable from the root of `tx` to `writer` as a complete database file and returns the
+/// number of bytes written. The pages are numbered anew in the order they are reached, which leaves out free pages,
+/// so the copy starts with an empty freelist. Trees keep their shape, only page ids change.
+pub(crate) fn write_to(tx: &impl ReadTx, writer: &mut impl Write) -> Result<u64, CustomError> {
+    let (root, txid) = match tx.dal().meta {
+        Some(ref meta) => (meta.root, meta.txid),
+        None => {
+            return Err(CustomError::new("Meta not initialized".to_string()));
+        }
+    };
+
+    // The new id of every page has to be known before the first page referring to it is written, so the trees
+    // are walked twice: once to number the pages and once to copy them.
+    let pages = match reachable_pages(tx, root) {
+        Ok(pages) => pages,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let page_ids: HashMap<u64, u64> = pages.iter().enumerate().map(|(i, (page_id, _))| (*page_id, BACKUP_FREELIST_PAGE + 1 + i as u64)).collect();
+
+    let meta = Meta {
+        root: page_ids[&root],
+        freelist_page: BACKUP_FREELIST_PAGE,
+        txid,
+    };
+    let freelist = Freelist {
+        max_page: BACKUP_FREELIST_PAGE + pages.len() as u64,
+        ..Freelist::new()
+    };
+    let mut pages_before_trees = vec![meta.serialize()];
+    pages_before_trees.extend(freelist.serialize());
+    let mut written = 0;
+    for data in pages_before_trees {
+        match write_page(writer, &data) {
+            Ok(()) => {
+                written += data.len() as u64;
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        }
+    }
+
+    for (page_id, tree) in pages {
+        let mut node = match tx.get_node(page_id) {
+            Ok(node) => node,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        for child in node.child_nodes.iter_mut() {
+            *child = page_ids[child];
+        }
+        if let Tree::Directory = tree {
+            for item in node.items.iter_mut() {
+                let collection = Collection::deserialize(item.clone());
+                let root = page_ids.get(&collection.root).copied().unwrap_or(u64::MAX);
+                let children = page_ids.get(&collection.children()).copied().unwrap_or(u64::MAX);
+                *item = collection.with_trees(root, children).serialize();
+            }
+        }
+
+        let data = node.serialize();
+        match write_page(writer, &data) {
+            Ok(()) => {
+                written += data.len() as u64;
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        }
+    }
+
+    match writer.flush() {
+        Ok(()) => Ok(written),
+        Err(error) => Err(CustomError::new(error.to_string()))
+    }
+}
+
+/// Writes the backup of `write_to` into a new file at `path`, which must not exist yet, and flushes it to the disk.
+/// The file is removed again when the backup fails.
+pub(crate) fn copy_file(tx: &impl ReadTx, path: &Path) -> Result<u64, CustomError> {
+    let mut file = match File::create_new(path) {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(CustomError::new(error.to_string()));
+        }
+    };
+
+    let written = match write_to(tx, &mut file) {
+        Ok(written) => match file.sync_all() {
+            Ok(()) => Ok(written),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+        Err(error) => Err(error)
+    };
+    match written {
+        Ok(written) => {
+            sync_directory(path);
+            Ok(written)
+        }
+        Err(error) => {
+            drop(file);
+            let _ = fs::remove_file(path);
+            Err(error)
+        }
+    }
+}
+
+/// Writes the pages written by commits after `since_txid` to `writer`, together with the meta and all freelist
+/// pages, as they are in `tx`. All pages are written when `since_txid` is 0 or earlier than the page txids go back.
+pub(crate) fn backup_incremental(tx: &impl ReadTx, since_txid: u64, writer: &mut impl Write) -> Result<BackupReport, CustomError> {
+    let dal = tx.dal();
+    let (txid, freelist_page) = match dal.meta {
+        Some(ref meta) => (meta.txid, meta.freelist_page),
+        None => {
+            return Err(CustomError::new("Meta not initialized".to_string()));
+        }
+    };
+    let (max_page, overflow_pages) = match dal.freelist {
+        Some(ref freelist) => (freelist.max_page, &freelist.overflow_pages),
+        None => {
+            return Err(CustomError::new("Freelist not initialized".to_string()));
+        }
+    };
+    if since_txid > txid {
+        return Err(CustomError::new(format!("Backup since txid {} is ahead of the database at txid {}", since_txid, txid)));
+    }
+
+    let (base_txid, page_txids) = match dal.page_txids {
+        Some(ref page_txids) => match page_txids.load() {
+            Ok(loaded) => (page_txids.base_txid(), loaded),
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        None => {
+            return Err(CustomError::new("Changed pages are not tracked for this database".to_string()));
+        }
+    };
+    let since_txid = if since_txid < base_txid { 0 } else { since_txid };
+    let pages: Vec<u64> = (0..=max_page).filter(|page_id| {
+        since_txid == 0 || *page_id == META_PAGE_NUM || *page_id == freelist_page || overflow_pages.contains(page_id) || page_txids.get(*page_id as usize).copied().unwrap_or(0) > since_txid
+    }).collect();
+
+    write_pages(&dal, since_txid, &pages, writer)
+}
+
+/// Writes a backup of `pages` as they are in `dal`, holding the changes since `since_txid`.
+pub(crate) fn write_pages(dal: &DAL, since_txid: u64, pages: &[u64], writer: &mut impl Write) -> Result<BackupReport, CustomError> {
+    let txid = match dal.meta {
+        Some(ref meta) => meta.txid,
+        None => {
+            return Err(CustomError::new("Meta not initialized".to_string()));
+        }
+    };
+    let max_page = match dal.freelist {
+        Some(ref freelist) => freelist.max_page,
+        None => {
+            return Err(CustomError::new("Freelist not initialized".to_string()));
+        }
+    };
+
+    let timestamp = SystemTime::now();
+    let header = BackupHeader {
+        page_size: dal.page_size() as u64,
+        since_txid,
+        txid,
+        timestamp: match timestamp.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_millis() as u64,
+            Err(_) => 0
+        },
+        max_page,
+        page_count: pages.len() as u64,
+    };
+    let mut bytes = BACKUP_HEADER_SIZE as u64;
+    match writer.write_all(&header.serialize()) {
+        Ok(()) => {},
+        Err(error) => {
+            return Err(CustomError::new(error.to_string()));
+        }
+    }
+
+    for page_id in pages {
+        let page = match dal.read_page(*page_id) {
+            Ok(page) => page,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        match writer.write_all(&page_id.to_le_bytes()).and_then(|()| writer.write_all(&page.data)) {
+            Ok(()) => {
+                bytes += (PAGE_ID_SIZE + page.data.len()) as u64;
+            }
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        }
+    }
+
+    match writer.flush() {
+        Ok(()) => {},
+        Err(error) => {
+            return Err(CustomError::new(error.to_string()));
+        }
+    }
+    Ok(BackupReport {
+        since_txid,
+        txid,
+        timestamp: UNIX_EPOCH + Duration::from_millis(header.timestamp),
+        pages: pages.len() as u64,
+        bytes,
+    })
+}
+
+/// Reads the header of a backup, leaving `backup` at its first page.
+pub(crate) fn read_report(backup: &mut impl Read) -> Result<BackupReport, CustomError> {
+    let mut buf = [0u8; BACKUP_HEADER_SIZE];
+    match backup.read_exact(&mut buf) {
+        Ok(()) => match BackupHeader::deserialize(buf) {
+            Ok(header) => Ok(BackupReport {
+                since_txid: header.since_txid,
+                txid: header.txid,
+                timestamp: UNIX_EPOCH + Duration::from_millis(header.timestamp),
+                pages: header.page_count,
+                bytes: BACKUP_HEADER_SIZE as u64 + header.page_count * (PAGE_ID_SIZE as u64 + header.page_size),
+            }),
+            Err(error) => Err(error)
+        }
+        Err(error) => Err(CustomError::new(error.to_string()))
+    }
+}
+
+/// Rebuilds a database file at `path`, which must not exist yet, from a full backup followed by incremental ones,
+/// each taken since a txid no later than the one before it ends at. Returns the txid of the restored database.
+/// The file is removed again when the restore fails.
+pub(crate) fn restore<R: Read>(path: &Path, backups: impl IntoIterator<Item = R>) -> Result<u64, CustomError> {
+    let mut file = match File::create_new(path) {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(CustomError::new(error.to_string()));
+        }
+    };
+
+    let restored = match apply_backups(&mut file, backups) {
+        Ok(txid) => match file.sync_all() {
+            Ok(()) => Ok(txid),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+        Err(error) => Err(error)
+    };
+    match restored {
+        Ok(txid) => {
+            sync_directory(path);
+            // Left behind by a database that was at `path` before.
+            match PageTxids::remove(path) {
+                Ok(()) => Ok(txid),
+                Err(error) => Err(error)
+            }
+        }
+        Err(error) => {
+            drop(file);
+            let _ = fs::remove_file(path);
+            Err(error)
+        }
+    }
+}
+
+fn apply_backups<R: Read>(file: &mut File, backups: impl IntoIterator<Item = R>) -> Result<u64, CustomError> {
+    let mut restored: Option<BackupHeader> = None;
+    for mut backup in backups {
+        let mut buf = [0u8; BACKUP_HEADER_SIZE];
+        let header = match backup.read_exact(&mut buf) {
+            Ok(()) => match BackupHeader::deserialize(buf) {
+                Ok(header) => header,
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+            Err(error) => {
+                return Err(CustomError::new(error.to_string()));
+            }
+        };
+        if header.page_size != PAGE_SIZE as u64 {
+            return Err(CustomError::new(format!("Backup has pages of {} bytes instead of {}", header.page_size, PAGE_SIZE)));
+        }
+        match restored {
+            None if header.since_txid != 0 => {
+                return Err(CustomError::new(format!("The first backup holds the changes since txid {} instead of being a full backup", header.since_txid)));
+            }
+            Some(ref previous) if header.since_txid > previous.txid || header.txid < previous.txid => {
+                return Err(CustomError::new(format!("Backup of txids {} to {} does not follow txid {}", header.since_txid, header.txid, previous.txid)));
+            }
+            _ => {}
+        }
+
+        let mut page_id_bytes = [0u8; PAGE_ID_SIZE];
+        let mut data = [0u8; PAGE_SIZE];
+        for _ in 0..header.page_count {
+            let copied = backup.read_exact(&mut page_id_bytes)
+                .and_then(|()| backup.read_exact(&mut data))
+                .and_then(|()| file.seek(SeekFrom::Start(u64::from_le_bytes(page_id_bytes) * header.page_size)))
+                .and_then(|_| file.write_all(&data));
+            match copied {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(CustomError::new(error.to_string()));
+                }
+            }
+        }
+        restored = Some(header);
+    }
+
+    match restored {
+        Some(header) => match file.set_len((header.max_page + 1) * header.page_size) {
+            Ok(()) => Ok(header.txid),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+        None => Err(CustomError::new("No backups to restore".to_string()))
+    }
+}
+
+/// The pages of every tree reachable from the root directory at `root`, each once, in the order they are reached.
+fn reachable_pages(tx: &impl ReadTx, root: u64) -> Result<Vec<(u64, Tree)>, CustomError> {
+    let mut pages = vec![];
+    let mut seen = HashSet::new();
+    let mut stack = vec![(root, Tree::Directory)];
+    while let Some((page_id, tree)) = stack.pop() {
+        if !seen.insert(page_id) {
+            return Err(CustomError::new(format!("Page {} is referenced more than once", page_id)));
+        }
+        let node = match tx.get_node(page_id) {
+            Ok(node) => node,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        // Pushed in reverse so the pages come out in key order.
+        if let Tree::Directory = tree {
+            for item in node.items.iter().rev() {
+                let collection = Collection::deserialize(item.clone());
+                if collection.children() != u64::MAX {
+                    stack.push((collection.children(), Tree::Directory));
+                }
+                if collection.root != u64::MAX {
+                    stack.push((collection.root, Tree::Values));
+                }
+            }
+        }
+        for child in node.child_nodes.iter().rev() {
+            stack.push((*child, tree));
+        }
+        pages.push((page_id, tree));
+    }
+
+    Ok(pages)
+}
+
+fn write_page(writer: &mut impl Write, data: &[u8; PAGE_SIZE]) -> Result<(), CustomError> {
+    match writer.write_all(data) {
+        Ok(()) => Ok(()),
+        Err(error) => Err(CustomError::new(error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::Arc, thread};
+
+    use crate::{consts::PAGE_SIZE, db::DB, test_util::{assert_contents, fill, open, open_existing, remove}, tx::ReadTx};
+
+    #[test]
+    fn write_to_leaves_out_free_pages() {
+        let db = open("./db_backup_test_internal_1");
+        fill(&db);
+        let stats = db.stats().unwrap();
+        assert!(stats.free_pages > 0);
+
+        let tx = db.read_tx().unwrap();
+        let mut backup = vec![];
+        let written = tx.write_to(&mut backup).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(written, backup.len() as u64);
+        assert_eq!(backup.len() as u64, (stats.max_page + 1 - stats.free_pages) * PAGE_SIZE as u64);
+
+        remove("./db_backup_test_internal_2");
+        fs::write("./db_backup_test_internal_2", &backup).unwrap();
+        let restored = open_existing("./db_backup_test_internal_2");
+        assert_contents(&restored);
+
+        let check = restored.check().unwrap();
+        assert!(check.is_ok(), "{:?}", check.errors().collect::<Vec<_>>());
+        let restored_stats = restored.stats().unwrap();
+        assert_eq!(restored_stats.free_pages, 0);
+        assert_eq!(restored_stats.trees, stats.trees);
+
+        // The copy takes further writes like any other database.
+        restored.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            for i in 0..1000 {
+                users.put(format!("user_{:05}", i), vec![1; 10], tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        assert!(restored.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn copy_file_alongside_readers() {
+        let db = Arc::new(open("./db_backup_test_internal_3"));
+        fill(&db);
+        remove("./db_backup_test_internal_4");
+
+        let tx = db.read_tx().unwrap();
+        let reader = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    db.view(|tx| tx.get_collection("users".to_string())?.unwrap().find("user_02990".to_string(), tx)).unwrap().unwrap();
+                }
+            })
+        };
+        let written = tx.copy_file("./db_backup_test_internal_4").unwrap();
+        assert!(tx.copy_file("./db_backup_test_internal_4").is_err());
+        tx.commit().unwrap();
+        reader.join().unwrap();
+        assert_eq!(written, fs::metadata("./db_backup_test_internal_4").unwrap().len());
+
+        let restored = open_existing("./db_backup_test_internal_4");
+        assert_contents(&restored);
+        assert!(restored.check().unwrap().is_ok());
+    }
+
+    /// Every item of every top-level collection, together with the name of its collection.
+    fn dump(db: &DB) -> Vec<(String, String, Vec<u8>)> {
+        db.view(|tx| {
+            let mut items = vec![];
+            for info in tx.list_collections()? {
+                let collection = tx.get_collection(info.name.clone())?.unwrap();
+                for item in collection.iter(tx) {
+                    let item = item?;
+                    items.push((info.name.clone(), item.key, item.value));
+                }
+            }
+            Ok(items)
+        }).unwrap()
+    }
+
+    #[test]
+    fn incremental_backups_restore_a_chain() {
+        let db = open("./db_backup_test_internal_5");
+        fill(&db);
+
+        let mut full = vec![];
+        let full_report = db.backup_incremental(0, &mut full).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(full_report.since_txid, 0);
+        assert_eq!(full_report.pages, stats.max_page + 1);
+        assert_eq!(full_report.bytes, full.len() as u64);
+
+        db.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            users.put("user_02990".to_string(), "changed".as_bytes().to_owned(), tx)
+        }).unwrap();
+        let mut first = vec![];
+        let first_report = db.backup_incremental(full_report.txid, &mut first).unwrap();
+        assert_eq!(first_report.since_txid, full_report.txid);
+        assert_eq!(first_report.txid, full_report.txid + 1);
+        // The meta page, the freelist page and the path from the root directory down to the changed leaf.
+        assert!(first_report.pages < 10, "{:?}", first_report);
+
+        db.update(|tx| {
+            let mut logs = tx.create_collection("logs".to_string())?;
+            for i in 0..300 {
+                logs.put(format!("log_{:04}", i), vec![3; 40], tx)?;
+            }
+            tx.delete_collection("empty".to_string())
+        }).unwrap();
+        db.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            for i in (1000..1500).filter(|i| i % 10 == 0) {
+                users.remove(format!("user_{:05}", i), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        let mut second = vec![];
+        let second_report = db.backup_incremental(first_report.txid, &mut second).unwrap();
+        assert_eq!(second_report.txid, first_report.txid + 2);
+        assert!(second_report.pages < full_report.pages);
+
+        remove("./db_backup_test_internal_6");
+        // A chain with a gap, or without a full backup first, is refused and leaves nothing behind.
+        assert!(DB::restore("./db_backup_test_internal_6", [&full[..], &second[..]]).is_err());
+        assert!(DB::restore("./db_backup_test_internal_6", [&first[..]]).is_err());
+        assert!(!Path::new("./db_backup_test_internal_6").exists());
+
+        let txid = DB::restore("./db_backup_test_internal_6", [&full[..], &first[..], &second[..]]).unwrap();
+        assert_eq!(txid, second_report.txid);
+        let restored = open_existing("./db_backup_test_internal_6");
+        assert_eq!(dump(&restored), dump(&db));
+        let check = restored.check().unwrap();
+        assert!(check.is_ok(), "{:?}", check.errors().collect::<Vec<_>>());
+
+        // The restored database carries on from the restored txid, and backups of it start with a full one.
+        let mut next = vec![];
+        assert_eq!(restored.backup_incremental(txid, &mut next).unwrap().since_txid, 0);
+        assert!(restored.backup_incremental(txid + 1, &mut next).is_err());
+
+        // Compaction moves every page, so the next backup is a full one.
+        db.compact().unwrap();
+        let mut after_compaction = vec![];
+        let report = db.backup_incremental(second_report.txid, &mut after_compaction).unwrap();
+        assert_eq!(report.since_txid, 0);
+        assert_eq!(report.txid, second_report.txid);
+    }
+}
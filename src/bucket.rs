@@ -0,0 +1,129 @@
+use crate::collection::{Collection, Iter};
+use crate::error::CustomError;
+use crate::node::Item;
+use crate::tx::{Tx, TxMut};
+
+/// A collection bound to the read-write transaction it was opened in, created by `TxMut::collection`.
+/// The handle borrows the transaction, so it cannot outlive it or be used with another one, and
+/// changes to the collection's root are tracked by the handle itself.
+pub struct Bucket<'tx, 'a> {
+    collection: Collection,
+    tx: &'tx mut TxMut<'a>,
+}
+
+impl <'tx, 'a> Bucket<'tx, 'a> {
+
+    pub(crate) fn new(collection: Collection, tx: &'tx mut TxMut<'a>) -> Bucket<'tx, 'a> {
+        Bucket {
+            collection,
+            tx,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.collection.name
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<Item>, CustomError> {
+        self.collection.find(key, &*self.tx)
+    }
+
+    pub fn put(&mut self, key: String, value: Vec<u8>) -> Result<(), CustomError> {
+        self.collection.put(key, value, self.tx)
+    }
+
+    pub fn delete(&mut self, key: String) -> Result<(), CustomError> {
+        self.collection.remove(key, self.tx)
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.collection.sequence()
+    }
+
+    /// Increments the sequence of the collection, see `Collection::next_sequence`.
+    pub fn next_sequence(&mut self) -> Result<u64, CustomError> {
+        self.collection.next_sequence(self.tx)
+    }
+
+    pub fn set_sequence(&mut self, sequence: u64) -> Result<(), CustomError> {
+        self.collection.set_sequence(sequence, self.tx)
+    }
+
+    /// Removes every key of the collection, see `Collection::clear`.
+    pub fn clear(&mut self) -> Result<(), CustomError> {
+        self.collection.clear(self.tx)
+    }
+
+    /// Iterates over the items of the collection in key order, including changes not yet committed.
+    pub fn iter(&self) -> Iter<'_, TxMut<'a>> {
+        self.collection.iter(&*self.tx)
+    }
+}
+
+/// A collection bound to the read-only transaction it was opened in, created by `Tx::collection`.
+pub struct ReadBucket<'tx, 'a> {
+    collection: Collection,
+    tx: &'tx Tx<'a>,
+}
+
+impl <'tx, 'a> ReadBucket<'tx, 'a> {
+
+    pub(crate) fn new(collection: Collection, tx: &'tx Tx<'a>) -> ReadBucket<'tx, 'a> {
+        ReadBucket {
+            collection,
+            tx,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.collection.name
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<Item>, CustomError> {
+        self.collection.find(key, self.tx)
+    }
+
+    /// Iterates over the items of the collection in key order.
+    pub fn iter(&self) -> Iter<'tx, Tx<'a>> {
+        self.collection.iter(self.tx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::open, tx::ReadTx};
+
+    #[test]
+    fn put_get_delete_iter() {
+        let db = open("./db_bucket_test_internal_1");
+
+        let mut tx = db.write_tx().unwrap();
+        tx.create_collection("test_collection".to_string()).unwrap();
+        assert!(tx.collection("missing_collection".to_string()).unwrap().is_none());
+
+        let mut bucket = tx.collection("test_collection".to_string()).unwrap().unwrap();
+        assert_eq!(bucket.name(), "test_collection");
+        // Enough keys to split the root several times, which the handle has to follow.
+        for i in 0..1000 {
+            bucket.put(format!("key_{:04}", i), format!("value_{}", i).as_bytes().to_owned()).unwrap();
+        }
+        for i in (0..20).step_by(2) {
+            bucket.delete(format!("key_{:04}", i)).unwrap();
+        }
+        assert_eq!(bucket.get("key_0001".to_string()).unwrap().unwrap().value, "value_1".as_bytes().to_owned());
+        assert!(bucket.get("key_0002".to_string()).unwrap().is_none());
+        assert_eq!(bucket.iter().count(), 990);
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        let collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert!(collection.find("key_0999".to_string(), &tx).unwrap().is_some());
+
+        let bucket = tx.collection("test_collection".to_string()).unwrap().unwrap();
+        let keys: Vec<String> = bucket.iter().map(|item| item.unwrap().key).collect();
+        let expected: Vec<String> = (0..1000).filter(|i| *i >= 20 || i % 2 == 1).map(|i| format!("key_{:04}", i)).collect();
+        assert_eq!(keys, expected);
+        tx.commit().unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashSet;
+
+use crate::{collection::Collection, consts::{COLLECTION_SIZE, PAGE_ID_SIZE}, error::CustomError, meta::META_PAGE_NUM, node::{Item, Node}, tx::ReadTx};
+
+/// What is wrong with a page, see `Violation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The page could not be read or does not hold a valid node.
+    Unreadable,
+    /// A page id beyond the freelist's `max_page`, or one of the meta and freelist pages, used for a node.
+    OutOfRange,
+    /// A page referenced from more than one place.
+    DuplicateReference,
+    /// Keys out of order within a node, or outside the range given by the keys of its ancestors.
+    KeyOrder,
+    /// An internal node whose number of children is not its number of items plus one.
+    ChildCount,
+    /// Leaves of a tree found at different depths.
+    LeafDepth,
+    /// A node other than the root of its tree without any item.
+    EmptyNode,
+    /// A node holding more than `max_fill_percent` of a page.
+    Overfilled,
+    /// A node other than the root of its tree holding less than `min_fill_percent` of a page. Splits, and
+    /// removals next to a node it cannot be merged with in one page, can leave nodes like this behind, so it is
+    /// only a warning.
+    Underfilled,
+    /// An entry of a collection directory that is not a valid collection.
+    BadEntry,
+    /// A page that is both reachable and on the freelist.
+    FreeAndReachable,
+    /// A page listed more than once on the freelist.
+    DuplicateFree,
+    /// A page at or below `max_page` that is neither reachable nor free, and so can never be reused.
+    Leaked,
+}
+
+impl ViolationKind {
+    /// Warnings point at wasted space rather than at data that is lost or about to be.
+    pub fn is_warning(&self) -> bool {
+        *self == ViolationKind::Underfilled
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub page_id: u64,
+    /// Path of the collection whose tree holds the page, separated by `/`. Empty for the directory of top-level
+    /// collections and for pages outside of any tree.
+    pub collection: String,
+    pub message: String,
+}
+
+/// Result of `DB::check`.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub max_page: u64,
+    /// Number of pages in use, including the meta and freelist pages.
+    pub reachable_pages: u64,
+    pub free_pages: u64,
+    /// Number of collections found, at any depth.
+    pub collections: u64,
+    pub violations: Vec<Violation>,
+}
+
+impl CheckReport {
+    /// Whether the check found no violation other than warnings.
+    pub fn is_ok(&self) -> bool {
+        self.violations.iter().all(|violation| violation.kind.is_warning())
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Violation> {
+        self.violations.iter().filter(|violation| !violation.kind.is_warning())
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Violation> {
+        self.violations.iter().filter(|violation| violation.kind.is_warning())
+    }
+}
+
+/// Key range a node's keys have to fall into, given by the items around it in its ancestors.
+struct Bounds<'k> {
+    lower: Option<&'k str>,
+    upper: Option<&'k str>,
+}
+
+struct Checker<'t, T: ReadTx> {
+    tx: &'t T,
+    min_threshold: f32,
+    max_threshold: f32,
+    freelist_page: u64,
+    reachable: HashSet<u64>,
+    report: CheckReport,
+}
+
+/// Walks the meta page, the freelist and every collection tree visible to `tx`, collecting every violation found
+/// instead of stopping at the first one.
+pub(crate) fn check(tx: &impl ReadTx) -> Result<CheckReport, CustomError> {
+    let (root, freelist_page, max_page, released_pages, duplicate_pages, overflow_pages, min_threshold, max_threshold) = {
+        let dal = tx.dal();
+        let (root, freelist_page) = match dal.meta {
+            Some(ref meta) => (meta.root, meta.freelist_page),
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()));
+            }
+        };
+        match dal.freelist {
+            Some(ref freelist) => (root, freelist_page, freelist.max_page, freelist.released_pages.clone(), freelist.duplicate_pages.clone(), freelist.overflow_pages.clone(), dal.min_threshold(), dal.max_threshold()),
+            None => {
+                return Err(CustomError::new("Freelist not initialized".to_string()));
+            }
+        }
+    };
+
+    let mut checker = Checker {
+        tx,
+        min_threshold,
+        max_threshold,
+        freelist_page,
+        reachable: HashSet::new(),
+        report: CheckReport {
+            max_page,
+            ..CheckReport::default()
+        },
+    };
+    checker.reachable.insert(META_PAGE_NUM);
+    if freelist_page == META_PAGE_NUM || freelist_page > max_page {
+        checker.violation(ViolationKind::OutOfRange, freelist_page, &[], format!("Freelist page {} is not a valid page", freelist_page));
+    } else {
+        checker.reachable.insert(freelist_page);
+    }
+    for page_id in overflow_pages {
+        if page_id == META_PAGE_NUM || page_id > max_page || !checker.reachable.insert(page_id) {
+            checker.violation(ViolationKind::OutOfRange, page_id, &[], format!("Freelist overflow page {} is not a valid page", page_id));
+        }
+    }
+
+    checker.check_directory(root, &[]);
+
+    for page_id in duplicate_pages {
+        checker.violation(ViolationKind::DuplicateFree, page_id, &[], format!("Page {} is on the freelist more than once", page_id));
+    }
+    for page_id in released_pages.iter().copied() {
+        if page_id == META_PAGE_NUM || page_id > max_page {
+            checker.violation(ViolationKind::OutOfRange, page_id, &[], format!("Freed page {} is not a valid page", page_id));
+        } else if checker.reachable.contains(&page_id) {
+            checker.violation(ViolationKind::FreeAndReachable, page_id, &[], format!("Page {} is in use and on the freelist", page_id));
+        }
+    }
+
+    for page_id in 0..=max_page {
+        if !checker.reachable.contains(&page_id) && !released_pages.contains(&page_id) {
+            checker.violation(ViolationKind::Leaked, page_id, &[], format!("Page {} is neither in use nor free", page_id));
+        }
+    }
+
+    checker.report.reachable_pages = checker.reachable.len() as u64;
+    checker.report.free_pages = released_pages.len() as u64;
+    Ok(checker.report)
+}
+
+impl<T: ReadTx> Checker<'_, T> {
+
+    fn violation(&mut self, kind: ViolationKind, page_id: u64, path: &[String], message: String) {
+        self.report.violations.push(Violation {
+            kind,
+            page_id,
+            collection: path.join("/"),
+            message,
+        });
+    }
+
+    /// Checks the directory tree rooted at `root` holding the entries of the sub-collections of the collection at
+    /// `path`, then each of those collections.
+    fn check_directory(&mut self, root: u64, path: &[String]) {
+        let mut entries = vec![];
+        self.check_tree(root, path, Some(&mut entries));
+
+        for entry in entries {
+            let mut child_path = path.to_vec();
+            child_path.push(entry.key.clone());
+
+            // Entries of 16 bytes were written before collections could have sub-collections.
+            if entry.value.len() != COLLECTION_SIZE && entry.value.len() != 2 * PAGE_ID_SIZE {
+                self.violation(ViolationKind::BadEntry, root, &child_path, format!("Collection entry of {} bytes", entry.value.len()));
+                continue;
+            }
+
+            self.report.collections += 1;
+            let collection = Collection::deserialize(entry);
+            self.check_tree(collection.root, &child_path, None);
+            self.check_directory(collection.children(), &child_path);
+        }
+    }
+
+    /// Checks the tree rooted at `root`, collecting its items in key order into `items` when given.
+    fn check_tree(&mut self, root: u64, path: &[String], items: Option<&mut Vec<Item>>) {
+        if root == u64::MAX {
+            return;
+        }
+
+        let mut leaf_depth = None;
+        let bounds = Bounds {
+            lower: None,
+            upper: None,
+        };
+        self.check_node(root, path, true, 0, &bounds, &mut leaf_depth, items);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_node(&mut self, page_id: u64, path: &[String], is_root: bool, depth: usize, bounds: &Bounds, leaf_depth: &mut Option<usize>, mut items: Option<&mut Vec<Item>>) {
+        if page_id == META_PAGE_NUM || page_id == self.freelist_page || page_id > self.report.max_page {
+            self.violation(ViolationKind::OutOfRange, page_id, path, format!("Node page {} is not a valid page", page_id));
+            return;
+        }
+        // A page seen before is not descended into again, so cycles end here.
+        if !self.reachable.insert(page_id) {
+            self.violation(ViolationKind::DuplicateReference, page_id, path, format!("Page {} is referenced more than once", page_id));
+            return;
+        }
+
+        let node: Node = match self.tx.get_node(page_id) {
+            Ok(node) => node,
+            Err(error) => {
+                self.violation(ViolationKind::Unreadable, page_id, path, error.message);
+                return;
+            }
+        };
+
+        self.check_keys(&node, path, bounds);
+
+        let size = node.node_size() as f32;
+        if size > self.max_threshold {
+            self.violation(ViolationKind::Overfilled, page_id, path, format!("Node of {} bytes is over the {} byte limit", size, self.max_threshold));
+        }
+        if !is_root {
+            if node.items.is_empty() {
+                self.violation(ViolationKind::EmptyNode, page_id, path, "Node without items".to_string());
+            } else if size < self.min_threshold {
+                self.violation(ViolationKind::Underfilled, page_id, path, format!("Node of {} bytes is under the {} byte minimum", size, self.min_threshold));
+            }
+        }
+
+        if node.is_leaf() {
+            match *leaf_depth {
+                Some(expected) if expected != depth => {
+                    self.violation(ViolationKind::LeafDepth, page_id, path, format!("Leaf at depth {}, other leaves are at depth {}", depth, expected));
+                }
+                Some(_) => {},
+                None => {
+                    *leaf_depth = Some(depth);
+                }
+            }
+            if let Some(items) = items {
+                items.extend(node.items);
+            }
+            return;
+        }
+
+        if node.child_nodes.len() != node.items.len() + 1 {
+            self.violation(ViolationKind::ChildCount, page_id, path, format!("Node with {} items has {} children", node.items.len(), node.child_nodes.len()));
+            return;
+        }
+
+        for (i, child) in node.child_nodes.iter().enumerate() {
+            let child_bounds = Bounds {
+                lower: if i == 0 { bounds.lower } else { Some(node.items[i - 1].key.as_str()) },
+                upper: if i == node.items.len() { bounds.upper } else { Some(node.items[i].key.as_str()) },
+            };
+            self.check_node(*child, path, false, depth + 1, &child_bounds, leaf_depth, items.as_deref_mut());
+            if i < node.items.len() {
+                if let Some(ref mut items) = items {
+                    items.push(node.items[i].clone());
+                }
+            }
+        }
+    }
+
+    fn check_keys(&mut self, node: &Node, path: &[String], bounds: &Bounds) {
+        for i in 0..node.items.len() {
+            let key = node.items[i].key.as_str();
+            if i > 0 && node.items[i - 1].key.as_str() >= key {
+                self.violation(ViolationKind::KeyOrder, node.page_id, path, format!("Key {:?} follows {:?}", key, node.items[i - 1].key));
+            }
+            if bounds.lower.is_some_and(|lower| key <= lower) || bounds.upper.is_some_and(|upper| key >= upper) {
+                self.violation(ViolationKind::KeyOrder, node.page_id, path, format!("Key {:?} is outside of the range {:?}..{:?} of its parent", key, bounds.lower, bounds.upper));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::open, tx::ReadTx};
+
+    use super::ViolationKind;
+
+    #[test]
+    fn healthy_database_passes() {
+        let db = open("./db_check_test_internal_1");
+
+        db.update(|tx| {
+            let mut tenants = tx.create_collection("tenants".to_string())?;
+            let mut acme = tenants.create_sub_collection("acme".to_string(), tx)?;
+            for i in 0..500 {
+                acme.put(format!("key_{:04}", i), format!("value_{}", i).as_bytes().to_owned(), tx)?;
+            }
+            for i in (0..500).step_by(3) {
+                acme.remove(format!("key_{:04}", i), tx)?;
+            }
+            tx.create_collection("empty".to_string())?;
+            Ok(())
+        }).unwrap();
+        db.update(|tx| tx.delete_collection("empty".to_string())).unwrap();
+
+        let report = db.check().unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors().collect::<Vec<_>>());
+        assert_eq!(report.collections, 2);
+        assert_eq!(report.reachable_pages + report.free_pages, report.max_page + 1);
+    }
+
+    #[test]
+    fn corruption_is_reported() {
+        let db = open("./db_check_test_internal_2");
+
+        db.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            for i in 0..500 {
+                collection.put(format!("key_{:04}", i), format!("value_{}", i).as_bytes().to_owned(), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+
+        let root = db.view(|tx| Ok(tx.get_collection("test_collection".to_string())?.unwrap().root)).unwrap();
+        {
+            let mut dal = db.write_dal();
+            let root_node = dal.get_node(root).unwrap();
+            let mut leaf = dal.get_node(root_node.child_nodes[0]).unwrap();
+            leaf.items.reverse();
+            dal.write_node(&mut leaf).unwrap();
+
+            // A page in use handed to the freelist, and a page taken from it that nothing points to.
+            dal.get_next_page().unwrap();
+            dal.delete_node(&root_node);
+            // As read from a freelist that lists the page twice.
+            dal.freelist.as_mut().unwrap().duplicate_pages.push(root);
+        }
+
+        let report = db.check().unwrap();
+        assert!(!report.is_ok());
+        let kinds: Vec<ViolationKind> = report.errors().map(|violation| violation.kind).collect();
+        assert!(kinds.contains(&ViolationKind::KeyOrder));
+        assert!(kinds.contains(&ViolationKind::FreeAndReachable));
+        assert!(kinds.contains(&ViolationKind::Leaked));
+        assert!(kinds.contains(&ViolationKind::DuplicateFree));
+        assert!(report.errors().all(|violation| violation.kind != ViolationKind::KeyOrder || violation.collection == "test_collection"));
+    }
+}
@@ -1,13 +1,72 @@
 use crate::consts::{COLLECTION_SIZE, PAGE_ID_SIZE};
 use crate::error::CustomError;
 use crate::node::{Item, Node};
-use crate::tx::{Tx, TxMut};
+use crate::tx::{ReadTx, TxMut};
 
-#[derive(Debug)]
+/// Summary of a collection returned by `ReadTx::list_collections`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectionInfo {
+    pub name: String,
+    /// Page id of the root node of the collection's tree.
+    pub root: u64,
+    pub key_count: u64,
+    /// See `Collection::sequence`.
+    pub sequence: u64,
+}
+
+/// Shape and size of a collection's tree, returned by `Collection::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CollectionStats {
+    /// Number of levels of the tree, 0 for an empty collection and 1 for a tree that is a single leaf.
+    pub depth: u64,
+    pub leaf_pages: u64,
+    pub branch_pages: u64,
+    pub key_count: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// Average size of the nodes of the tree as a percentage of `DAL::max_threshold`.
+    pub average_fill_percent: f32,
+}
+
+impl CollectionStats {
+    /// Adds the counts of `other` to these, keeping the greater depth.
+    pub fn merge(&mut self, other: &CollectionStats) {
+        let pages = self.leaf_pages + self.branch_pages;
+        let other_pages = other.leaf_pages + other.branch_pages;
+        if pages + other_pages > 0 {
+            self.average_fill_percent = (self.average_fill_percent * pages as f32 + other.average_fill_percent * other_pages as f32) / (pages + other_pages) as f32;
+        }
+
+        self.depth = self.depth.max(other.depth);
+        self.leaf_pages += other.leaf_pages;
+        self.branch_pages += other.branch_pages;
+        self.key_count += other.key_count;
+        self.key_bytes += other.key_bytes;
+        self.value_bytes += other.value_bytes;
+    }
+}
+
+/// Where a collection's own entry is kept, so that `TxMut::update_collection` knows what to rewrite when its
+/// root moves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Location {
+    /// The directory of top-level collections, whose root lives in the meta page.
+    Root,
+    /// An entry in the directory of sub-collections of the collection at this path. An empty path stands for
+    /// the directory of top-level collections.
+    Entry(Vec<String>),
+    /// The directory of sub-collections of the collection at this path.
+    Directory(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
 pub struct Collection {
     pub name: String,
     pub root: u64,
-    counter: u64
+    counter: u64,
+    /// Root page of the tree holding the entries of the sub-collections, `u64::MAX` while there are none.
+    children: u64,
+    location: Location,
 }
 
 impl Collection  {
@@ -17,15 +76,97 @@ impl Collection  {
             name,
             root,
             counter: 0,
+            children: u64::MAX,
+            location: Location::Entry(vec![]),
         }
     }
 
     pub fn empty() -> Collection {
+        Collection::new("".to_string(), u64::MAX)
+    }
+
+    pub(crate) fn root_directory(root: u64) -> Collection {
         Collection {
-            name: "".to_string(),
-            root: u64::MAX,
-            counter: 0,
+            location: Location::Root,
+            ..Collection::new("".to_string(), root)
+        }
+    }
+
+    pub(crate) fn location(&self) -> &Location {
+        &self.location
+    }
+
+    /// Takes over the fields that are changed through the stored entry rather than through this handle.
+    pub(crate) fn keep_stored(&mut self, stored: &Collection) {
+        self.counter = stored.counter;
+        self.children = stored.children;
+    }
+
+    /// A copy of this collection with its trees at other pages, as written by compaction and backups.
+    pub(crate) fn with_trees(&self, root: u64, children: u64) -> Collection {
+        Collection {
+            root,
+            children,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn children(&self) -> u64 {
+        self.children
+    }
+
+    pub(crate) fn set_children(&mut self, children: u64) {
+        self.children = children;
+    }
+
+    /// The path identifying this collection among the nested collections, empty for the root directory.
+    fn path(&self) -> Vec<String> {
+        match self.location {
+            Location::Entry(ref parent_path) => {
+                let mut path = parent_path.clone();
+                path.push(self.name.clone());
+                path
+            }
+            Location::Root | Location::Directory(_) => vec![]
+        }
+    }
+
+    /// The tree holding the entries of this collection's sub-collections. For the root directory that is
+    /// the root directory itself.
+    pub(crate) fn sub_directory(&self) -> Collection {
+        match self.location {
+            Location::Root => self.clone(),
+            _ => Collection {
+                location: Location::Directory(self.path()),
+                ..Collection::new("".to_string(), self.children)
+            }
+        }
+    }
+
+    /// Loads the collection at `path`, descending through the sub-collections of each collection on the way.
+    pub(crate) fn at_path(path: &[String], tx: &impl ReadTx) -> Result<Option<Collection>, CustomError> {
+        let mut collection = match tx.get_root_collection() {
+            Ok(root_collection) => root_collection,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        for name in path {
+            match collection.get_sub_collection(name.clone(), tx) {
+                Ok(Some(child)) => {
+                    collection = child;
+                }
+                Ok(None) => {
+                    return Ok(None);
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            }
         }
+
+        Ok(Some(collection))
     }
 
     pub fn serialize(&mut self) -> Item {
@@ -36,6 +177,9 @@ impl Collection  {
         left_pos += PAGE_ID_SIZE;
 
         bytes[left_pos..left_pos+PAGE_ID_SIZE].clone_from_slice(&self.counter.to_le_bytes());
+        left_pos += PAGE_ID_SIZE;
+
+        bytes[left_pos..left_pos+PAGE_ID_SIZE].clone_from_slice(&self.children.to_le_bytes());
         
         let bytes_as_str = unsafe {
             std::str::from_utf8_unchecked(&bytes)
@@ -48,59 +192,90 @@ impl Collection  {
         let mut collection = Collection::empty();
         collection.name = item.key;
 
-        if item.value.len() > 0 {
+        if !item.value.is_empty() {
             let buf = item.value;
 
             let mut left_pos = 0;
             let mut u64_bytes = [0u8; PAGE_ID_SIZE];
-            for n in 0..PAGE_ID_SIZE {
-                u64_bytes[n] = buf[left_pos+n];
-            }
+            u64_bytes.copy_from_slice(&buf[left_pos..left_pos+PAGE_ID_SIZE]);
             left_pos += PAGE_ID_SIZE;
             collection.root = u64::from_le_bytes(u64_bytes);
 
             u64_bytes = [0u8; PAGE_ID_SIZE];
-            for n in 0..PAGE_ID_SIZE {
-                u64_bytes[n] = buf[left_pos+n];
-            }
+            u64_bytes.copy_from_slice(&buf[left_pos..left_pos+PAGE_ID_SIZE]);
+            left_pos += PAGE_ID_SIZE;
             collection.counter = u64::from_le_bytes(u64_bytes);
+
+            // 16 byte entries were written before collections could have sub-collections.
+            if buf.len() >= COLLECTION_SIZE {
+                u64_bytes = [0u8; PAGE_ID_SIZE];
+                u64_bytes.copy_from_slice(&buf[left_pos..left_pos+PAGE_ID_SIZE]);
+                collection.children = u64::from_le_bytes(u64_bytes);
+            }
         }
 
         collection
     }
 
+    /// Hands out ids from the in-memory counter, which is not saved. Use `next_sequence` for ids that are unique
+    /// across transactions.
     pub fn id(&mut self) -> u64 {
         let id = self.counter;
         self.counter += 1;
-        return id;
+        id
     }
 
-    pub fn find(&self, key: String, tx: &Tx) -> Result<Option<Item>, CustomError> {
-        let root = tx.get_node(self.root);
-        match root {
-            Ok(root) => {
-                match root.find_key(&key, true, tx) {
-                    Ok((index, containing_node, _)) => {
-                        if index == usize::MAX {
-                            return Ok(None);
-                        }
-                        
-                        Ok(Some(containing_node.items[index].clone()))
-                    }
+    /// The current value of the collection's sequence, as of when this handle was loaded or last changed it.
+    pub fn sequence(&self) -> u64 {
+        self.counter
+    }
+
+    /// Increments the sequence and returns its new value, so the first call returns 1. The change is saved as part
+    /// of `tx` and undone if it rolls back.
+    pub fn next_sequence(&mut self, tx: &mut TxMut) -> Result<u64, CustomError> {
+        match self.reload(tx) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        match self.counter.checked_add(1) {
+            Some(sequence) => {
+                match self.set_sequence(sequence, tx) {
+                    Ok(()) => Ok(sequence),
                     Err(error) => Err(error)
                 }
             }
-            Err(error) => Err(error)
+            None => Err(CustomError::new(format!("Sequence of collection {} overflowed", self.name)))
         }
+    }
 
-        
+    /// Sets the sequence to `sequence` as part of `tx`.
+    pub fn set_sequence(&mut self, sequence: u64, tx: &mut TxMut) -> Result<(), CustomError> {
+        if self.location == Location::Root {
+            return Err(CustomError::new("The root collection has no sequence".to_string()));
+        }
+
+        match self.reload(tx) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        self.counter = sequence;
+        tx.write_collection(self, false)
     }
 
-    pub fn find_mut(&self, key: String, tx: &TxMut) -> Result<Option<Item>, CustomError> {
+    pub fn find(&self, key: String, tx: &impl ReadTx) -> Result<Option<Item>, CustomError> {
+        if self.root == u64::MAX {
+            return Ok(None);
+        }
+
         let root = tx.get_node(self.root);
         match root {
             Ok(root) => {
-                match root.find_key_mut(&key, true, tx) {
+                match root.find_key(&key, true, tx) {
                     Ok((index, containing_node, _)) => {
                         if index == usize::MAX {
                             return Ok(None);
@@ -117,6 +292,102 @@ impl Collection  {
         
     }
 
+    /// Counts the keys of the collection, which reads every node of its tree.
+    pub fn info(&self, tx: &impl ReadTx) -> Result<CollectionInfo, CustomError> {
+        let mut key_count = 0;
+        for item in self.iter(tx) {
+            match item {
+                Ok(_) => {
+                    key_count += 1;
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(CollectionInfo {
+            name: self.name.clone(),
+            root: self.root,
+            key_count,
+            sequence: self.counter,
+        })
+    }
+
+    /// Reads every node of the collection's tree to measure it. Sub-collections are not included.
+    pub fn stats(&self, tx: &impl ReadTx) -> Result<CollectionStats, CustomError> {
+        let mut stats = CollectionStats::default();
+        if self.root == u64::MAX {
+            return Ok(stats);
+        }
+
+        let max_threshold = tx.dal().max_threshold();
+        let mut fill_total = 0.0;
+        let mut to_visit = vec![(self.root, 1)];
+        while let Some((page_id, depth)) = to_visit.pop() {
+            let node = match tx.get_node(page_id) {
+                Ok(node) => node,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+
+            stats.depth = stats.depth.max(depth);
+            if node.is_leaf() {
+                stats.leaf_pages += 1;
+            } else {
+                stats.branch_pages += 1;
+            }
+            stats.key_count += node.items.len() as u64;
+            for item in node.items.iter() {
+                stats.key_bytes += item.key.len() as u64;
+                stats.value_bytes += item.value.len() as u64;
+            }
+            fill_total += node.node_size() as f32 / max_threshold * 100.0;
+
+            to_visit.extend(node.child_nodes.iter().map(|child| (*child, depth + 1)));
+        }
+        stats.average_fill_percent = fill_total / (stats.leaf_pages + stats.branch_pages) as f32;
+
+        Ok(stats)
+    }
+
+    /// Collects the page ids of every node of the collection's tree.
+    pub fn pages(&self, tx: &impl ReadTx) -> Result<Vec<u64>, CustomError> {
+        let mut pages = vec![];
+        if self.root == u64::MAX {
+            return Ok(pages);
+        }
+
+        let mut to_visit = vec![self.root];
+        while let Some(page_id) = to_visit.pop() {
+            match tx.get_node(page_id) {
+                Ok(node) => {
+                    pages.push(page_id);
+                    to_visit.extend(node.child_nodes);
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Iterates over the items of the collection in key order.
+    pub fn iter<'tx, T: ReadTx>(&self, tx: &'tx T) -> Iter<'tx, T> {
+        let mut iter = Iter {
+            tx,
+            stack: vec![],
+            error: None,
+        };
+        if self.root != u64::MAX {
+            iter.descend(self.root);
+        }
+        iter
+    }
+
     pub fn put(&mut self, key: String, value: Vec<u8>, tx: &mut TxMut) -> Result<(), CustomError> {
 
         let item = Item::new(key.clone(), value);
@@ -149,11 +420,11 @@ impl Collection  {
             }
         }
 
-        match root.find_key_mut(&item.key, false, tx) {
+        match root.find_key(&item.key, false, tx) {
             Ok((insertion_index, node_to_insert_in, ancestors_index)) => {
                 let mut node_to_insert_in = node_to_insert_in;
                 
-                if insertion_index < node_to_insert_in.items.len() && node_to_insert_in.items[insertion_index].key == *(&item.key) {
+                if insertion_index < node_to_insert_in.items.len() && node_to_insert_in.items[insertion_index].key == item.key {
                     node_to_insert_in.items[insertion_index] = item;
                 } else {
                     node_to_insert_in.add_item(item, insertion_index);
@@ -213,93 +484,313 @@ impl Collection  {
                 Ok(())
             }
             Err(error) => {
-                return Err(error);
+                Err(error)
             }
         }
     }
 
     pub fn remove(&mut self, key: String, tx: &mut TxMut) -> Result<(), CustomError> {
-        match tx.get_node(self.root) {
-            Ok(root) => {
-                match root.find_key_mut(&key, true, tx) {
-                    Ok((remove_item_index, mut node_to_remove_from, mut ancestor_indexes)) => {
+        if self.root == u64::MAX {
+            return Ok(());
+        }
 
-                        if remove_item_index == usize::MAX {
-                            return Ok(());
-                        }
+        let root = match tx.get_node(self.root) {
+            Ok(root) => root,
+            Err(error) => {
+                return Err(error);
+            }
+        };
 
-                        if node_to_remove_from.is_leaf() {
-                            node_to_remove_from.remove_item_from_leaf(remove_item_index, tx);
-                        } else {
-                            match node_to_remove_from.remove_item_from_internal(remove_item_index, tx) {
-                                Ok(affected_nodes) => {
-                                    ancestor_indexes.extend(affected_nodes);
-
-                                    match self.get_nodes(&ancestor_indexes, tx) {
-                                        Ok(mut ancestors) => {
-                                            for i in (0..=ancestors.len()-2).rev() {
-                                                let mut p_node = ancestors[i].clone();
-                                                let mut node = ancestors[i+1].clone();
-                                                if node.is_under_populated(tx) {
-                                                    match p_node.rebalance_remove(&mut node, ancestor_indexes[i+1], tx) {
-                                                        Ok(()) => {
-                                                            let root = ancestors[0].clone();
-                                                            if root.items.len() == 0 && root.child_nodes.len() > 0 {
-                                                                tx.delete_node(&root);
-                                                                self.root = ancestors[1].page_id;
-                                                                match tx.update_collection(self) {
-                                                                    Ok(()) => {}
-                                                                    Err(error) => {
-                                                                        return Err(error);
-                                                                    }
-                                                                }
-                                                            }
-                                                        }
-                                                        Err(error) => {
-                                                            return Err(error);
-                                                        }
-                                                    }
-                                                }
-                                                ancestors[i] = p_node;
-                                                ancestors[i+1] = node;
-                                            }
-                                        }
-                                        Err(error) => {
-                                            return Err(error);
-                                        }
-                                    }
-                                }
-                                Err(error) => {
-                                    return Err(error);
-                                }
-                            }
-                        }
+        let (remove_item_index, mut node_to_remove_from, mut ancestor_indexes) = match root.find_key(&key, true, tx) {
+            Ok(found) => found,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        if remove_item_index == usize::MAX {
+            return Ok(());
+        }
+
+        if node_to_remove_from.is_leaf() {
+            node_to_remove_from.remove_item_from_leaf(remove_item_index, tx);
+        } else {
+            match node_to_remove_from.remove_item_from_internal(remove_item_index, tx) {
+                Ok(affected_nodes) => ancestor_indexes.extend(affected_nodes),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        let mut ancestors = match self.get_nodes(&ancestor_indexes, tx) {
+            Ok(ancestors) => ancestors,
+            Err(error) => {
+                return Err(error);
+            }
+        };
 
-                        Ok(())
+        // Rebalance from the parent of the node the item was taken from up to the root, which may stay small.
+        for i in (0..ancestors.len()-1).rev() {
+            let mut p_node = ancestors[i].clone();
+            let mut node = ancestors[i+1].clone();
+            if node.is_under_populated(tx) {
+                match p_node.rebalance_remove(&mut node, ancestor_indexes[i+1], tx) {
+                    Ok(()) => {}
+                    Err(error) => {
+                        return Err(error);
                     }
-                    Err(error) => Err(error)
                 }
             }
+            ancestors[i] = p_node;
+            ancestors[i+1] = node;
+        }
+
+        // A root left without items after a merge is replaced by its only child.
+        let root = &ancestors[0];
+        if root.items.is_empty() && !root.child_nodes.is_empty() {
+            tx.delete_node(root);
+            self.root = root.child_nodes[0];
+            return tx.update_collection(self);
+        }
+
+        Ok(())
+    }
+
+    /// Removes every key, releasing all pages of the tree and starting over from a new empty root.
+    /// The name and sequence of the collection are kept. A directory of collections cannot be cleared, since
+    /// the trees of its collections would be left behind; they are deleted one by one instead.
+    pub fn clear(&mut self, tx: &mut TxMut) -> Result<(), CustomError> {
+        if let Location::Root | Location::Directory(_) = self.location {
+            return Err(CustomError::new("Cannot clear a directory of collections".to_string()));
+        }
+
+        let pages = match self.pages(tx) {
+            Ok(pages) => pages,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        let mut root = match tx.new_node(vec![], vec![]) {
+            Ok(node) => node,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        match tx.write_node(&mut root) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        for page_id in pages {
+            tx.delete_page(page_id);
+        }
+        self.root = root.page_id;
+        tx.update_collection(self)
+    }
+
+    /// Creates the sub-collection `name`, stored in a tree of its own next to this collection's items.
+    pub fn create_sub_collection(&mut self, name: String, tx: &mut TxMut) -> Result<Collection, CustomError> {
+        match self.get_sub_collection(name.clone(), tx) {
+            Ok(Some(_)) => {
+                return Err(CustomError::new(format!("Collection already exists: {}", name)));
+            }
+            Ok(None) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        let mut root = match tx.new_node(vec![], vec![]) {
+            Ok(node) => node,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        match tx.write_node(&mut root) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        let mut collection = Collection {
+            location: Location::Entry(self.path()),
+            ..Collection::new(name, root.page_id)
+        };
+        match tx.update_collection(&mut collection) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        match self.reload(tx) {
+            Ok(()) => Ok(collection),
             Err(error) => Err(error)
         }
+    }
 
+    pub fn get_sub_collection(&self, name: String, tx: &impl ReadTx) -> Result<Option<Collection>, CustomError> {
+        match self.sub_directory().find(name, tx) {
+            Ok(Some(item)) => {
+                let mut collection = Collection::deserialize(item);
+                collection.location = Location::Entry(self.path());
+                Ok(Some(collection))
+            }
+            Ok(None) => Ok(None),
+            Err(error) => Err(error)
+        }
     }
 
-    fn get_nodes(&mut self, indexes: &Vec<usize>, tx: &mut TxMut) -> Result<Vec<Node>, CustomError> {
-        let root: Node;
-        match tx.get_node(self.root) {
-            Ok(node) => {
-                root = node;
+    /// Deletes the sub-collection `name` together with its own sub-collections, releasing all their pages.
+    pub fn delete_sub_collection(&mut self, name: String, tx: &mut TxMut) -> Result<(), CustomError> {
+        let pages = match self.get_sub_collection(name.clone(), tx) {
+            Ok(Some(collection)) => {
+                match collection.all_pages(tx) {
+                    Ok(pages) => pages,
+                    Err(error) => {
+                        return Err(error);
+                    }
+                }
+            }
+            Ok(None) => {
+                return Ok(());
             }
             Err(error) => {
                 return Err(error);
             }
+        };
+
+        match self.sub_directory().remove(name, tx) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        match self.reload(tx) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        for page_id in pages {
+            tx.delete_page(page_id);
+        }
+        Ok(())
+    }
+
+    /// Lists the sub-collections in name order, see `ReadTx::list_collections`.
+    pub fn list_sub_collections(&self, tx: &impl ReadTx) -> Result<Vec<CollectionInfo>, CustomError> {
+        let sub_collections = match self.sub_collections(tx) {
+            Ok(sub_collections) => sub_collections,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        let mut collections = vec![];
+        for collection in sub_collections {
+            match collection.info(tx) {
+                Ok(info) => collections.push(info),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(collections)
+    }
+
+    /// Loads the sub-collections in name order.
+    pub(crate) fn sub_collections(&self, tx: &impl ReadTx) -> Result<Vec<Collection>, CustomError> {
+        let mut collections = vec![];
+        for item in self.sub_directory().iter(tx) {
+            match item {
+                Ok(item) => collections.push(Collection {
+                    location: Location::Entry(self.path()),
+                    ..Collection::deserialize(item)
+                }),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(collections)
+    }
+
+    /// Collects the pages of the collection's tree and of all its sub-collections, at any depth.
+    pub fn all_pages(&self, tx: &impl ReadTx) -> Result<Vec<u64>, CustomError> {
+        let mut pages = match self.pages(tx) {
+            Ok(pages) => pages,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        if self.location == Location::Root || self.children == u64::MAX {
+            return Ok(pages);
+        }
+
+        let sub_directory = self.sub_directory();
+        match sub_directory.pages(tx) {
+            Ok(directory_pages) => pages.extend(directory_pages),
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        for item in sub_directory.iter(tx) {
+            let child = match item {
+                Ok(item) => Collection {
+                    location: Location::Entry(self.path()),
+                    ..Collection::deserialize(item)
+                },
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            match child.all_pages(tx) {
+                Ok(child_pages) => pages.extend(child_pages),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Picks up the sequence and sub-collection tree root as stored, which other handles may have changed.
+    fn reload(&mut self, tx: &impl ReadTx) -> Result<(), CustomError> {
+        if !matches!(self.location, Location::Entry(_)) {
+            return Ok(());
+        }
+
+        match Collection::at_path(&self.path(), tx) {
+            Ok(Some(collection)) => {
+                self.keep_stored(&collection);
+                Ok(())
+            }
+            Ok(None) => Err(CustomError::new(format!("Collection not found: {}", self.name))),
+            Err(error) => Err(error)
         }
+    }
+
+    fn get_nodes(&mut self, indexes: &[usize], tx: &mut TxMut) -> Result<Vec<Node>, CustomError> {
+        let root = match tx.get_node(self.root) {
+            Ok(node) => node,
+            Err(error) => {
+                return Err(error);
+            }
+        };
 
         let mut child = root.clone();
         let mut nodes = vec![root];
-        for i in 1..indexes.len() {
-            match tx.get_node(child.child_nodes[indexes[i]]) {
+        for index in &indexes[1..] {
+            match tx.get_node(child.child_nodes[*index]) {
                 Ok(node) => {
                     child = node.clone();
                     nodes.push(node);
@@ -315,11 +806,79 @@ impl Collection  {
 
 }
 
+struct Frame {
+    node: Node,
+    index: usize,
+}
+
+/// In-order traversal of a collection, created by `Collection::iter`. Only the nodes on the path to the
+/// current item are held in memory.
+pub struct Iter<'tx, T: ReadTx> {
+    tx: &'tx T,
+    stack: Vec<Frame>,
+    error: Option<CustomError>,
+}
+
+impl <T: ReadTx> Iter<'_, T> {
+
+    fn descend(&mut self, page_id: u64) {
+        let mut page_id = page_id;
+        loop {
+            match self.tx.get_node(page_id) {
+                Ok(node) => {
+                    let is_leaf = node.is_leaf();
+                    if !is_leaf {
+                        page_id = node.child_nodes[0];
+                    }
+                    self.stack.push(Frame {
+                        node,
+                        index: 0,
+                    });
+                    if is_leaf {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    self.error = Some(error);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl <T: ReadTx> Iterator for Iter<'_, T> {
+    type Item = Result<Item, CustomError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            self.stack.clear();
+            return Some(Err(error));
+        }
+
+        loop {
+            let frame = self.stack.last_mut()?;
+            if frame.index < frame.node.items.len() {
+                let item = frame.node.items[frame.index].clone();
+                frame.index += 1;
+                if !frame.node.is_leaf() {
+                    let child = frame.node.child_nodes[frame.index];
+                    self.descend(child);
+                }
+                return Some(Ok(item));
+            }
+
+            self.stack.pop();
+        }
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
-    use crate::{dal::{Options, DEFAULT_OPTIONS}, db::DB};
+    use crate::{dal::{Options, DEFAULT_OPTIONS}, db::DB, node::Item, test_util::{new_options, open}, tx::{ReadTx, TxMut}};
     use core::panic;
-    use std::{fs, path::Path};
+    use std::{collections::BTreeMap, fs, path::Path};
 
     use super::Collection;
 
@@ -329,7 +888,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_collection_test_internal_1"
+            path: "./db_collection_test_internal_1",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -343,7 +903,7 @@ mod tests {
 
         match DB::open(options) {
             Ok(db) => {
-                let mut tx = db.write_tx();
+                let mut tx = db.write_tx().unwrap();
 
                 let mut root_node;
                 match tx.new_node(vec![], vec![]) {
@@ -372,7 +932,7 @@ mod tests {
 
                 match collection.put(key1.clone(), value1.clone(), &mut tx) {
                     Ok(()) => {
-                        match collection.find_mut(key1.clone(), &mut tx) {
+                        match collection.find(key1.clone(), &tx) {
                             Ok(optional_item) => {
                                 match optional_item {
                                     Some(item) => {
@@ -400,7 +960,7 @@ mod tests {
 
                 match collection.put(key2.clone(), value2.clone(), &mut tx) {
                     Ok(()) => {
-                        match collection.find_mut(key2.clone(), &mut tx) {
+                        match collection.find(key2.clone(), &tx) {
                             Ok(optional_item) => {
                                 match optional_item {
                                     Some(item) => {
@@ -446,7 +1006,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_collection_test_internal_2"
+            path: "./db_collection_test_internal_2",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -460,7 +1021,7 @@ mod tests {
 
         match DB::open(options) {
             Ok(ref mut db) => {
-                let mut tx = db.write_tx();
+                let mut tx = db.write_tx().unwrap();
 
                 let mut root_node;
                 match tx.new_node(vec![], vec![]) {
@@ -506,7 +1067,7 @@ mod tests {
                     }
                 }
 
-                let tx = db.read_tx();
+                let tx = db.read_tx().unwrap();
 
                 for i in 1..=1000 {
                     let key = format!("key{}", i);
@@ -534,14 +1095,14 @@ mod tests {
                     }
                 }
 
-                let mut tx = db.write_tx();
+                let mut tx = db.write_tx().unwrap();
 
                 for i in 1..=1000 {
                     let key = format!("key{}", i);
                     
                     match collection.remove(key.clone(), &mut tx) {
                         Ok(()) => {
-                            match collection.find_mut(key.clone(), &mut tx) {
+                            match collection.find(key.clone(), &tx) {
                                 Ok(Some(item)) => {
                                     assert!(false, "Item not removed: {:?}", item);
                                 }
@@ -576,4 +1137,307 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn clear_collection() {
+        let db = open("./db_collection_test_internal_3");
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+        for i in 0..500 {
+            collection.put(format!("key_{:03}", i), format!("value_{}", i).as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        collection.next_sequence(&mut tx).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        let pages = collection.pages(&tx).unwrap();
+        collection.clear(&mut tx).unwrap();
+        assert!(collection.find("key_001".to_string(), &tx).unwrap().is_none());
+        assert_eq!(collection.iter(&tx).count(), 0);
+        tx.commit().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let info = &tx.list_collections().unwrap()[0];
+        assert_eq!(info.name, "test_collection".to_string());
+        assert_eq!(info.key_count, 0);
+        assert_eq!(info.sequence, 1);
+        let mut collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert_eq!(collection.pages(&tx).unwrap().len(), 1);
+        for page_id in &pages {
+            assert!(tx.dal().freelist.as_ref().unwrap().released_pages.contains(page_id));
+        }
+
+        collection.put("key".to_string(), "value".as_bytes().to_owned(), &mut tx).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        let collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert!(collection.find("key".to_string(), &tx).unwrap().is_some());
+        tx.commit().unwrap();
+
+        // Clearing the directory of collections would leak their trees.
+        let mut tx = db.write_tx().unwrap();
+        assert!(tx.get_root_collection().unwrap().clear(&mut tx).is_err());
+        tx.commit().unwrap();
+        assert!(db.view(|tx| tx.get_collection("test_collection".to_string())).unwrap().is_some());
+        assert!(db.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn nested_collections() {
+        let options = new_options("./db_collection_test_internal_4");
+
+        let db = DB::open(options.clone()).unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let mut tenant = tx.create_collection("tenant_a".to_string()).unwrap();
+        tenant.put("plan".to_string(), "free".as_bytes().to_owned(), &mut tx).unwrap();
+        let mut stale_tenant = tx.get_collection("tenant_a".to_string()).unwrap().unwrap();
+        let mut users = tenant.create_sub_collection("users".to_string(), &mut tx).unwrap();
+        let mut orders = tenant.create_sub_collection("orders".to_string(), &mut tx).unwrap();
+        assert!(tenant.create_sub_collection("users".to_string(), &mut tx).is_err());
+
+        // Enough records and sub-collections to split both the record tree and the sub-collection directory.
+        for i in 0..300 {
+            users.put(format!("user_{:03}", i), format!("name_{}", i).as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        for i in 0..200 {
+            let mut day = orders.create_sub_collection(format!("day_{:03}", i), &mut tx).unwrap();
+            day.put("order".to_string(), format!("order_{}", i).as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        // This handle predates the sub-collections, writing its entry on a root split must not lose them.
+        for i in 0..300 {
+            stale_tenant.put(format!("setting_{:03}", i), "value".as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = DB::open(options).unwrap();
+        let tx = db.read_tx().unwrap();
+        let names: Vec<String> = tx.list_collections().unwrap().into_iter().map(|info| info.name).collect();
+        assert_eq!(names, vec!["tenant_a".to_string()]);
+
+        let tenant = tx.get_collection("tenant_a".to_string()).unwrap().unwrap();
+        let keys: Vec<String> = tenant.iter(&tx).map(|item| item.unwrap().key).collect();
+        assert_eq!(keys.len(), 301);
+        assert_eq!(keys[0], "plan".to_string());
+        let sub_collections = tenant.list_sub_collections(&tx).unwrap();
+        assert_eq!(sub_collections.len(), 2);
+        assert_eq!(sub_collections[0].name, "orders".to_string());
+        assert_eq!(sub_collections[1].name, "users".to_string());
+        assert_eq!(sub_collections[1].key_count, 300);
+
+        let users = tenant.get_sub_collection("users".to_string(), &tx).unwrap().unwrap();
+        assert_eq!(users.find("user_123".to_string(), &tx).unwrap().unwrap().value, "name_123".as_bytes().to_owned());
+        let orders = tenant.get_sub_collection("orders".to_string(), &tx).unwrap().unwrap();
+        assert_eq!(orders.list_sub_collections(&tx).unwrap().len(), 200);
+        let day = orders.get_sub_collection("day_150".to_string(), &tx).unwrap().unwrap();
+        assert_eq!(day.find("order".to_string(), &tx).unwrap().unwrap().value, "order_150".as_bytes().to_owned());
+        assert!(tenant.get_sub_collection("day_150".to_string(), &tx).unwrap().is_none());
+        tx.commit().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let mut tenant = tx.get_collection("tenant_a".to_string()).unwrap().unwrap();
+        tenant.delete_sub_collection("users".to_string(), &mut tx).unwrap();
+        assert!(tenant.get_sub_collection("users".to_string(), &tx).unwrap().is_none());
+        let pages = tenant.all_pages(&tx).unwrap();
+        tx.delete_collection("tenant_a".to_string()).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        assert!(tx.get_collection("tenant_a".to_string()).unwrap().is_none());
+        // Freed pages at the end of the file are cut off instead of being listed.
+        let dal = tx.dal();
+        let freelist = dal.freelist.as_ref().unwrap();
+        for page_id in &pages {
+            assert!(freelist.released_pages.contains(page_id) || *page_id > freelist.max_page);
+        }
+    }
+
+    #[test]
+    fn persistent_sequences() {
+        let options = new_options("./db_collection_test_internal_5");
+
+        let db = DB::open(options.clone()).unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+        let mut other_handle = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert_eq!(collection.sequence(), 0);
+        assert_eq!(collection.next_sequence(&mut tx).unwrap(), 1);
+        assert_eq!(collection.next_sequence(&mut tx).unwrap(), 2);
+        // Another handle continues from the stored value instead of its own stale copy.
+        assert_eq!(other_handle.next_sequence(&mut tx).unwrap(), 3);
+        // Root splits rewrite the entry of the handle, which must not move the sequence back.
+        for i in 0..300 {
+            collection.put(format!("key_{:03}", i), "value".as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        assert_eq!(collection.sequence(), 3);
+        tx.commit().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert_eq!(collection.next_sequence(&mut tx).unwrap(), 4);
+        tx.rollback().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert_eq!(collection.sequence(), 3);
+        collection.set_sequence(100, &mut tx).unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = DB::open(options).unwrap();
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert_eq!(collection.sequence(), 100);
+        assert_eq!(collection.next_sequence(&mut tx).unwrap(), 101);
+        let mut root_collection = tx.get_root_collection().unwrap();
+        assert!(root_collection.next_sequence(&mut tx).is_err());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn collection_stats() {
+        let db = open("./db_collection_test_internal_7");
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+        let empty = collection.stats(&tx).unwrap();
+        assert_eq!(empty.key_count, 0);
+        assert_eq!(empty.leaf_pages + empty.branch_pages, 1);
+
+        for i in 0..500 {
+            collection.put(format!("key_{:04}", i), vec![0u8; 20], &mut tx).unwrap();
+        }
+
+        let stats = collection.stats(&tx).unwrap();
+        assert_eq!(stats.depth, 2);
+        assert_eq!(stats.branch_pages, 1);
+        assert_eq!(stats.leaf_pages + stats.branch_pages, collection.pages(&tx).unwrap().len() as u64);
+        assert_eq!(stats.key_count, 500);
+        assert_eq!(stats.key_bytes, 500 * 8);
+        assert_eq!(stats.value_bytes, 500 * 20);
+        assert!(stats.average_fill_percent > 50.0 && stats.average_fill_percent <= 100.0);
+        tx.commit().unwrap();
+    }
+
+    /// xorshift64*, enough randomness for the property test without pulling in a crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545F4914F6CDD1D)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    /// Checks the B-tree invariants below `page_id` and returns the depth of its leaves.
+    fn check_node(page_id: u64, is_root: bool, tx: &TxMut, items: &mut Vec<Item>) -> usize {
+        let node = tx.get_node(page_id).unwrap();
+        assert!(!tx.dal().is_over_populated(&node), "Node {} is over populated", page_id);
+        if !is_root {
+            assert!(!node.items.is_empty(), "Node {} is empty", page_id);
+        }
+        // A root left without items by a merge is replaced by its only child.
+        assert!(!is_root || node.is_leaf() || !node.items.is_empty(), "Root {} was not collapsed", page_id);
+
+        if node.is_leaf() {
+            items.extend(node.items);
+            return 1;
+        }
+
+        assert_eq!(node.child_nodes.len(), node.items.len() + 1, "Node {} has a wrong number of children", page_id);
+        let mut depth = 0;
+        for (i, child) in node.child_nodes.iter().enumerate() {
+            let child_depth = check_node(*child, false, tx, items);
+            if i == 0 {
+                depth = child_depth;
+            }
+            assert_eq!(child_depth, depth, "Leaves below node {} are at different depths", page_id);
+            if i < node.items.len() {
+                items.push(node.items[i].clone());
+            }
+        }
+        depth + 1
+    }
+
+    /// Checks that no node below the root on the way to `key` is left under populated. Removing a key rebalances
+    /// the nodes on that way, while splits elsewhere may leave smaller nodes behind.
+    fn check_removal_path(root: u64, key: &String, tx: &TxMut) {
+        let mut node = tx.get_node(root).unwrap();
+        let (_, _, ancestor_indexes) = node.find_key(key, false, tx).unwrap();
+        for index in ancestor_indexes.into_iter().skip(1) {
+            node = tx.get_node(node.child_nodes[index]).unwrap();
+            assert!(!tx.dal().is_under_populated(&node), "Node {} is under populated after removing {}", node.page_id, key);
+        }
+    }
+
+    #[test]
+    fn random_puts_and_removes_match_btree_map() {
+        // A node under the minimum and a neighbour that cannot spare an item are only sure to fit in one page when
+        // the maximum is well over twice the minimum. With the default fill range neither merging nor rotating can
+        // always bring both up to it.
+        let db = DB::open(Options {
+            min_fill_percent: 0.4,
+            ..new_options("./db_collection_test_internal_6")
+        }).unwrap();
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        let mut expected: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+
+        let mut tx = db.write_tx().unwrap();
+        tx.create_collection("test_collection".to_string()).unwrap();
+        tx.commit().unwrap();
+
+        for round in 0..60 {
+            let mut tx = db.write_tx().unwrap();
+            let mut collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+
+            // Rounds alternate between growing and shrinking so the tree both splits and collapses.
+            let put_percent = if round % 6 < 3 { 70 } else { 25 };
+            for _ in 0..100 {
+                let key = format!("key_{:04}", rng.below(1000));
+                if rng.below(100) < put_percent {
+                    let value: Vec<u8> = (0..rng.below(60)).map(|_| rng.below(26) as u8 + b'a').collect();
+                    collection.put(key.clone(), value.clone(), &mut tx).unwrap();
+                    expected.insert(key, value);
+                } else {
+                    collection.remove(key.clone(), &mut tx).unwrap();
+                    if expected.remove(&key).is_some() {
+                        check_removal_path(collection.root, &key, &tx);
+                        // Taking a key out of an internal node replaces it with the one before it, from a leaf.
+                        if let Some((previous, _)) = expected.range(..key.clone()).next_back() {
+                            check_removal_path(collection.root, previous, &tx);
+                        }
+                    }
+                }
+
+                let mut items = vec![];
+                check_node(collection.root, true, &tx, &mut items);
+                assert_eq!(items.len(), expected.len());
+                for (item, (key, value)) in items.iter().zip(expected.iter()) {
+                    assert_eq!(&item.key, key);
+                    assert_eq!(&item.value, value);
+                }
+            }
+            tx.commit().unwrap();
+
+            // Every page is either in use by a tree, on the freelist, or the meta or freelist page.
+            let tx = db.read_tx().unwrap();
+            let collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+            let mut pages = collection.pages(&tx).unwrap();
+            pages.extend(tx.get_root_collection().unwrap().pages(&tx).unwrap());
+            pages.extend(tx.dal().freelist.as_ref().unwrap().released_pages.iter());
+            pages.extend(tx.dal().freelist.as_ref().unwrap().overflow_pages.iter());
+            pages.sort();
+            let max_page = tx.dal().freelist.as_ref().unwrap().max_page;
+            assert_eq!(pages, (2..=max_page).collect::<Vec<u64>>());
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::{fs::File, mem, path::Path, sync::Arc};
+
+use crate::{collection::Collection, dal::{Options, DAL}, error::CustomError, metrics::Metrics, node::{Item, Node}, tx::ReadTx};
+
+/// Result of `DB::compact_to` and `DB::compact`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    pub size_before: u64,
+    pub size_after: u64,
+    /// Number of collections copied, at any depth.
+    pub collections: u64,
+    pub keys: u64,
+}
+
+struct Level {
+    node: Node,
+    /// The full node before `node` together with the item between the two. It is written once the level moves on,
+    /// so the last two nodes of a level can be evened out when the tree is finished.
+    held: Option<(Node, Item)>,
+}
+
+/// Writes a tree from items given in key order straight into new pages, filling each node up to
+/// `max_fill_percent` before starting the next one. Only the last two nodes of each level can end up less full.
+struct TreeBuilder<'d> {
+    dal: &'d mut DAL,
+    levels: Vec<Level>,
+}
+
+impl<'d> TreeBuilder<'d> {
+
+    fn new(dal: &'d mut DAL) -> TreeBuilder<'d> {
+        TreeBuilder {
+            dal,
+            levels: vec![Level {
+                node: Node::new(u64::MAX, vec![], vec![]),
+                held: None,
+            }],
+        }
+    }
+
+    fn add(&mut self, item: Item) -> Result<(), CustomError> {
+        self.push_item(0, item)
+    }
+
+    fn push_item(&mut self, level: usize, item: Item) -> Result<(), CustomError> {
+        let node = &mut self.levels[level].node;
+        node.items.push(item);
+        if node.items.len() == 1 || !self.dal.is_over_populated(node) {
+            return Ok(());
+        }
+
+        // The item does not fit, so it separates the full node from the next one.
+        let item = match node.items.pop() {
+            Some(item) => item,
+            None => {
+                return Err(CustomError::new("Node lost its last item".to_string()));
+            }
+        };
+        let full = mem::replace(node, Node::new(u64::MAX, vec![], vec![]));
+        match self.levels[level].held.replace((full, item)) {
+            Some((held, separator)) => {
+                let page_id = match self.write(held) {
+                    Ok(page_id) => page_id,
+                    Err(error) => {
+                        return Err(error);
+                    }
+                };
+                self.push_child(level + 1, page_id);
+                self.push_item(level + 1, separator)
+            }
+            None => Ok(())
+        }
+    }
+
+    fn push_child(&mut self, level: usize, page_id: u64) {
+        if self.levels.len() == level {
+            self.levels.push(Level {
+                node: Node::new(u64::MAX, vec![], vec![]),
+                held: None,
+            });
+        }
+        self.levels[level].node.child_nodes.push(page_id);
+    }
+
+    fn write(&mut self, mut node: Node) -> Result<u64, CustomError> {
+        match self.dal.write_node(&mut node) {
+            Ok(()) => Ok(node.page_id),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Writes the nodes still pending on every level and returns the page of the root.
+    fn finish(mut self) -> Result<u64, CustomError> {
+        let mut level = 0;
+        loop {
+            let node = mem::replace(&mut self.levels[level].node, Node::new(u64::MAX, vec![], vec![]));
+            let nodes = match self.levels[level].held.take() {
+                Some((mut left, separator)) => {
+                    let mut right = node;
+                    match self.even_out(&mut left, separator, &mut right) {
+                        Some(separator) => {
+                            let left_page_id = match self.write(left) {
+                                Ok(page_id) => page_id,
+                                Err(error) => {
+                                    return Err(error);
+                                }
+                            };
+                            self.push_child(level + 1, left_page_id);
+                            match self.push_item(level + 1, separator) {
+                                Ok(()) => {},
+                                Err(error) => {
+                                    return Err(error);
+                                }
+                            }
+                            vec![right]
+                        }
+                        None => vec![left]
+                    }
+                }
+                None => vec![node]
+            };
+
+            for node in nodes {
+                let page_id = match self.write(node) {
+                    Ok(page_id) => page_id,
+                    Err(error) => {
+                        return Err(error);
+                    }
+                };
+                if level + 1 == self.levels.len() {
+                    return Ok(page_id);
+                }
+                self.push_child(level + 1, page_id);
+            }
+            level += 1;
+        }
+    }
+
+    /// Merges the last two nodes of a level when they fit in one, returning `None`. Otherwise moves items from
+    /// `left` to `right` until `right` is no longer under populated, and returns the item between them.
+    fn even_out(&self, left: &mut Node, separator: Item, right: &mut Node) -> Option<Item> {
+        let mut merged = left.clone();
+        merged.items.push(separator.clone());
+        merged.items.extend(right.items.iter().cloned());
+        merged.child_nodes.extend(right.child_nodes.iter().copied());
+        if !self.dal.is_over_populated(&merged) {
+            *left = merged;
+            return None;
+        }
+
+        let mut separator = separator;
+        while self.dal.is_under_populated(right) && left.items.len() > 1 {
+            if let Some(item) = left.items.pop() {
+                right.items.insert(0, mem::replace(&mut separator, item));
+            }
+            if let Some(child) = left.child_nodes.pop() {
+                right.child_nodes.insert(0, child);
+            }
+        }
+        Some(separator)
+    }
+}
+
+/// Copies every collection visible to `tx`, at any depth, into a new file at `path` that holds nothing else, so
+/// it has no free pages and its nodes are as full as `max_fill_percent` allows.
+pub(crate) fn compact(tx: &impl ReadTx, path: &Path, options: &Options, metrics: Arc<Metrics>) -> Result<CompactReport, CustomError> {
+    let size_before = match tx.dal().file_size() {
+        Ok(size) => size,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let txid = match tx.dal().meta {
+        Some(ref meta) => meta.txid,
+        None => {
+            return Err(CustomError::new("Meta not initialized".to_string()));
+        }
+    };
+    let mut dal = match DAL::create_file(path, options, metrics) {
+        Ok(dal) => dal,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let mut report = CompactReport {
+        size_before,
+        ..CompactReport::default()
+    };
+
+    let root_collection = match tx.get_root_collection() {
+        Ok(root_collection) => root_collection,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let root = match copy_directory(&root_collection, tx, &mut dal, &mut report) {
+        Ok(root) => root,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    match dal.meta {
+        Some(ref mut meta) => {
+            meta.root = root;
+            meta.txid = txid;
+        }
+        None => {
+            return Err(CustomError::new("Meta not created correctly".to_string()));
+        }
+    }
+
+    match dal.write_freelist() {
+        Ok(_) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    }
+    match dal.write_meta() {
+        Ok(_) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    }
+    match dal.sync() {
+        Ok(()) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    }
+
+    match dal.file_size() {
+        Ok(size) => {
+            report.size_after = size;
+        }
+        Err(error) => {
+            return Err(error);
+        }
+    }
+    Ok(report)
+}
+
+/// Makes a rename in the directory of `path` durable. Not every platform can open a directory, so this is best
+/// effort.
+pub(crate) fn sync_directory(path: &Path) {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+    if let Ok(directory) = File::open(directory) {
+        let _ = directory.sync_all();
+    }
+}
+
+/// Copies the sub-collections of `owner` and returns the root page of the new tree holding their entries.
+fn copy_directory(owner: &Collection, tx: &impl ReadTx, dal: &mut DAL, report: &mut CompactReport) -> Result<u64, CustomError> {
+    let sub_collections = match owner.sub_collections(tx) {
+        Ok(sub_collections) => sub_collections,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    let mut entries = vec![];
+    for collection in sub_collections {
+        let mut root = u64::MAX;
+        if collection.root != u64::MAX {
+            let mut builder = TreeBuilder::new(dal);
+            for item in collection.iter(tx) {
+                let added = match item {
+                    Ok(item) => builder.add(item),
+                    Err(error) => Err(error)
+                };
+                match added {
+                    Ok(()) => {
+                        report.keys += 1;
+                    }
+                    Err(error) => {
+                        return Err(error);
+                    }
+                }
+            }
+            root = match builder.finish() {
+                Ok(root) => root,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+
+        let mut children = u64::MAX;
+        if collection.children() != u64::MAX {
+            children = match copy_directory(&collection, tx, dal, report) {
+                Ok(children) => children,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+        }
+
+        report.collections += 1;
+        entries.push(collection.with_trees(root, children).serialize());
+    }
+
+    let mut builder = TreeBuilder::new(dal);
+    for entry in entries {
+        match builder.add(entry) {
+            Ok(()) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+    }
+    builder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{test_util::{assert_contents, fill, open, open_existing, remove}, tx::ReadTx};
+
+    #[test]
+    fn compact_to_new_file() {
+        let db = open("./db_compact_test_internal_1");
+        fill(&db);
+        remove("./db_compact_test_internal_2");
+
+        let report = db.compact_to("./db_compact_test_internal_2").unwrap();
+        assert_eq!(report.collections, 4);
+        assert_eq!(report.keys, 400);
+        assert!(report.size_after < report.size_before);
+        assert!(db.compact_to("./db_compact_test_internal_2").is_err());
+
+        let compacted = open_existing("./db_compact_test_internal_2");
+        assert_contents(&compacted);
+
+        let check = compacted.check().unwrap();
+        assert!(check.is_ok(), "{:?}", check.errors().collect::<Vec<_>>());
+        let stats = compacted.stats().unwrap();
+        assert_eq!(stats.free_pages, 0);
+        assert_eq!(stats.file_size, (stats.max_page + 1) * stats.page_size);
+        // About 7000 bytes of keys and values, which fit in two full leaves.
+        let users = compacted.view(|tx| tx.get_collection("users".to_string())?.unwrap().stats(tx)).unwrap();
+        assert_eq!(users.leaf_pages, 2);
+        assert_eq!(users.branch_pages, 1);
+
+        // The compacted trees take further writes like any other.
+        compacted.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            for i in (0..3000).filter(|i| i % 10 == 5) {
+                users.put(format!("user_{:05}", i), vec![1; 10], tx)?;
+            }
+            for i in (0..1500).filter(|i| i % 10 == 0) {
+                users.remove(format!("user_{:05}", i), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        assert!(compacted.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn compact_in_place() {
+        let db = open("./db_compact_test_internal_3");
+        fill(&db);
+        let size_before = fs::metadata("./db_compact_test_internal_3").unwrap().len();
+
+        let report = db.compact().unwrap();
+        assert_eq!(report.size_before, size_before);
+        assert_eq!(fs::metadata("./db_compact_test_internal_3").unwrap().len(), report.size_after);
+        assert!(!Path::new("./db_compact_test_internal_3.compact").exists());
+        assert_contents(&db);
+        assert_eq!(db.stats().unwrap().free_pages, 0);
+
+        // Transactions keep working against the new file, and it reopens.
+        db.update(|tx| {
+            let mut users = tx.get_collection("users".to_string())?.unwrap();
+            users.put("user_99999".to_string(), vec![1], tx)
+        }).unwrap();
+        drop(db);
+
+        let db = open_existing("./db_compact_test_internal_3");
+        let tx = db.read_tx().unwrap();
+        assert!(tx.get_collection("users".to_string()).unwrap().unwrap().find("user_99999".to_string(), &tx).unwrap().is_some());
+        tx.commit().unwrap();
+        assert!(db.check().unwrap().is_ok());
+    }
+}
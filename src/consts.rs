@@ -1,4 +1,4 @@
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_ID_SIZE: usize = 8;
-pub const COLLECTION_SIZE: usize = 16;
\ No newline at end of file
+pub const COLLECTION_SIZE: usize = 24;
\ No newline at end of file
@@ -1,5 +1,5 @@
-use crate::{consts::PAGE_SIZE, error::CustomError, freelist::Freelist, meta::{Meta, META_PAGE_NUM}, node::Node};
-use std::{fs::File, io::{Read, Seek, SeekFrom, Write}};
+use crate::{backup::PageTxids, consts::PAGE_SIZE, error::{CustomError, ErrorKind}, freelist::Freelist, meta::{Meta, META_PAGE_NUM}, metrics::{Metrics, Observer}, node::Node};
+use std::{fs::{self, File, TryLockError}, io::{self, Read, Seek, SeekFrom, Write}, sync::Arc, thread, time::{Duration, Instant}};
 use std::path::Path;
 
 #[derive(Clone)]
@@ -8,15 +8,42 @@ pub struct Options {
     pub min_fill_percent: f32,
     pub max_fill_percent: f32,
     pub path: &'static str,
+
+    /// Maximum number of `DB::batch` calls coalesced into a single transaction.
+    pub max_batch_size: usize,
+    /// Maximum time a `DB::batch` call waits for other calls to join its transaction.
+    pub max_batch_delay: Duration,
+
+    /// Told about every page read and written, split, merge, rotation, commit and rollback, see `DB::metrics`.
+    pub observer: Option<Arc<dyn Observer>>,
+
+    /// Directory every commit is archived in, for `DB::restore_to`. Nothing is archived when unset.
+    pub archive_dir: Option<&'static str>,
+
+    /// Opens the file for reading only, sharing it with other read-only processes. Write transactions fail and
+    /// the file is not created when missing.
+    pub read_only: bool,
+    /// How long to wait for other processes to close the file, for ever when unset. Opening for writing needs
+    /// the file to itself, opening read-only only waits for a writer.
+    pub lock_timeout: Option<Duration>,
 }
 
 pub const DEFAULT_OPTIONS: Options = Options {
     page_size: PAGE_SIZE,
     min_fill_percent: 0.5,
     max_fill_percent: 0.95,
-    path: "./db"
+    path: "./db",
+    max_batch_size: 1000,
+    max_batch_delay: Duration::from_millis(10),
+    observer: None,
+    archive_dir: None,
+    read_only: false,
+    lock_timeout: Some(Duration::from_secs(1)),
 };
 
+/// How often a locked file is tried again while waiting for `Options::lock_timeout`.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 #[derive(Debug)]
 pub struct Page {
     id: u64,
@@ -31,57 +58,204 @@ pub struct DAL {
     max_fill_percent: f32,
 
     pub meta: Option<Meta>,
-    pub freelist: Option<Freelist>
+    pub freelist: Option<Freelist>,
+    /// Only kept for the file of an open database, see `DB::backup_incremental`.
+    pub(crate) page_txids: Option<PageTxids>,
+
+    pub(crate) metrics: Arc<Metrics>,
 }
 
 impl DAL {
     pub fn new_dal(options: Options) -> Result<DAL, CustomError> {
-        let path = options.path;
-        if !Path::new(&path).exists() {
-            match File::create(path) {
+        let metrics = Arc::new(Metrics::new(options.observer.clone()));
+        DAL::open_at(Path::new(options.path), &options, metrics)
+    }
+
+    /// Opens the database file at `path`, creating it when it does not exist. `options.path` is not used.
+    pub(crate) fn open_at(path: &Path, options: &Options, metrics: Arc<Metrics>) -> Result<DAL, CustomError> {
+        let mut created = false;
+        if !path.exists() {
+            if options.read_only {
+                return Err(CustomError::new(format!("Database {} does not exist", path.display())));
+            }
+
+            match File::create_new(path) {
                 Ok(file) => {
-                    let mut dal = DAL {
-                        file,
-                        page_size: options.page_size,
-                        min_fill_percent: options.min_fill_percent,
-                        max_fill_percent: options.max_fill_percent,
-                        meta: Some(Meta::new()),
-                        freelist: Some(Freelist::new()),
+                    // Locked before anything is written, other processes wait for the file to be complete.
+                    let written = match DAL::new_file(file, path, options, metrics.clone()) {
+                        Ok(mut dal) => dal.write_new_file(),
+                        Err(error) => Err(error)
                     };
-                    let page_id: u64; 
-                    match dal.get_next_page() {
-                        Ok(_page_id) => {
-                            page_id = _page_id
-                        }
+                    match written {
+                        Ok(()) => {},
                         Err(error) => {
+                            // A partial file would be taken for an existing database by the next open.
+                            let _ = fs::remove_file(path);
                             return Err(error);
                         }
                     }
-                    
-                    match dal.meta {
-                        Some(ref mut meta) => {
-                            meta.freelist_page = page_id;
-                        }
-                        None => {
-                            return Err(CustomError::new("Meta not created correctly".to_string()));
-                        }
+                    created = true;
+                }
+                // Another process created it first, it is opened like any existing file.
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(error) => {
+                    return Err(CustomError::new(error.to_string()));
+                }
+            }
+        }
+        
+        let mut dal = match DAL::open_locked(path, options, metrics) {
+            Ok(dal) => dal,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        if options.read_only {
+            return Ok(dal);
+        }
+
+        match dal.track_page_txids(path, created) {
+            Ok(()) => Ok(dal),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Writes the root collection, freelist and meta page of a file made by `create_file`.
+    fn write_new_file(&mut self) -> Result<(), CustomError> {
+        let mut root_collection = Node::new(u64::MAX, vec![], vec![]);
+        match self.write_node(&mut root_collection) {
+            Ok(()) => {
+                match self.meta {
+                    Some(ref mut meta) => {
+                        meta.root = root_collection.page_id;
                     }
-                    match dal.write_freelist() {
-                        Ok(_) => {},
-                        Err(error) => {
-                            return Err(error);
-                        }
+                    None => {
+                        return Err(CustomError::new("Meta not created correctly".to_string()));
                     }
-                    
-                    let mut root_collection = Node::new(u64::MAX, vec![], vec![]);
-                    match dal.write_node(&mut root_collection) {
-                        Ok(()) => {
-                            match dal.meta {
-                                Some(ref mut meta) => {
-                                    meta.root = root_collection.page_id;
+                }
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        // Written after the root collection so the page it took is not handed out again.
+        match self.write_freelist() {
+            Ok(_) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        match self.write_meta() {
+            Ok(_) => Ok(()),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Opens the file at `path`, locks it and reads its meta page and freelist. Changed pages are not tracked yet.
+    /// A file without a whole meta page is still being created by another process, which holds its lock until it
+    /// is complete, and is waited for like a locked one.
+    pub(crate) fn open_locked(path: &Path, options: &Options, metrics: Arc<Metrics>) -> Result<DAL, CustomError> {
+        let started = Instant::now();
+        let mut dal = loop {
+            let mut dal = match DAL::open_file(path, options, !options.read_only, metrics.clone()) {
+                Ok(dal) => dal,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            match dal.lock(path, options, started) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+
+            match dal.file_size() {
+                Ok(size) if size >= dal.page_size as u64 => {
+                    break dal;
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+            // Lets go of the lock, the process creating the file may be waiting for it.
+            drop(dal);
+            match options.lock_timeout {
+                Some(timeout) if started.elapsed() >= timeout => {
+                    return Err(CustomError::with_kind(ErrorKind::Locked, format!("Database {} is being created by another process", path.display())));
+                }
+                _ => thread::sleep(LOCK_RETRY_INTERVAL)
+            }
+        };
+
+        match dal.read_meta() {
+            Ok(meta) => {
+                dal.meta = Some(meta);
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        
+        match dal.read_freelist() {
+            Ok(freelist) => {
+                dal.freelist = Some(freelist);
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        Ok(dal)
+    }
+
+    /// Starts recording the txid of every page written, in the page txids next to the database at `path`. They
+    /// are started over when `fresh` is set.
+    pub(crate) fn track_page_txids(&mut self, path: &Path, fresh: bool) -> Result<(), CustomError> {
+        let txid = match self.meta {
+            Some(ref meta) => meta.txid,
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()));
+            }
+        };
+        match PageTxids::open(path, txid, fresh) {
+            Ok(page_txids) => {
+                self.page_txids = Some(page_txids);
+                Ok(())
+            }
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Takes an advisory lock on the file for as long as it is open: an exclusive one to write, a shared one to only
+    /// read. Fails with a locked error when another process holds a conflicting lock for `options.lock_timeout`
+    /// after `started`.
+    fn lock(&mut self, path: &Path, options: &Options, started: Instant) -> Result<(), CustomError> {
+        loop {
+            let locked = if options.read_only {
+                self.file.try_lock_shared()
+            } else {
+                self.file.try_lock()
+            };
+            match locked {
+                Ok(()) => {
+                    // A compaction may have renamed another file over `path` while this one waited, and the lock
+                    // on the replaced file protects nothing. The new file is opened and locked instead.
+                    match is_file_at(&self.file, path) {
+                        Ok(true) => {
+                            return Ok(());
+                        }
+                        Ok(false) => {
+                            match File::options().read(true).write(!options.read_only).open(path) {
+                                Ok(file) => {
+                                    self.file = file;
+                                    continue;
                                 }
-                                None => {
-                                    return Err(CustomError::new("Meta not created correctly".to_string()));
+                                Err(error) => {
+                                    return Err(CustomError::new(error.to_string()));
                                 }
                             }
                         }
@@ -89,55 +263,155 @@ impl DAL {
                             return Err(error);
                         }
                     }
-
-                    match dal.write_meta() {
-                        Ok(_) => {},
-                        Err(error) => {
-                            return Err(error);
-                        }
-                    }
                 }
-                Err(error) => {
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(error)) => {
                     return Err(CustomError::new(error.to_string()));
                 }
             }
+
+            match options.lock_timeout {
+                Some(timeout) if started.elapsed() >= timeout => {
+                    return Err(CustomError::with_kind(ErrorKind::Locked, format!("Database {} is locked by another process", path.display())));
+                }
+                _ => thread::sleep(LOCK_RETRY_INTERVAL)
+            }
         }
+    }
+
+    /// Creates a new file at `path`, failing when it exists, with the freelist page allocated. Nothing is written
+    /// yet, meta and freelist are only set in memory.
+    pub(crate) fn create_file(path: &Path, options: &Options, metrics: Arc<Metrics>) -> Result<DAL, CustomError> {
+        match File::create_new(path) {
+            Ok(file) => DAL::new_file(file, path, options, metrics),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+    }
+
+    /// Locks `file`, just created at `path`, and allocates its freelist page.
+    fn new_file(file: File, path: &Path, options: &Options, metrics: Arc<Metrics>) -> Result<DAL, CustomError> {
+        let mut dal = DAL {
+            file,
+            page_size: options.page_size,
+            min_fill_percent: options.min_fill_percent,
+            max_fill_percent: options.max_fill_percent,
+            meta: Some(Meta::new()),
+            freelist: Some(Freelist::new()),
+            page_txids: None,
+            metrics,
+        };
+        match dal.lock(path, options, Instant::now()) {
+            Ok(()) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        let page_id = match dal.get_next_page() {
+            Ok(page_id) => page_id,
+            Err(error) => {
+                return Err(error);
+            }
+        };
         
-        match File::options().read(true).write(true).open(path) {
-            Ok(file) => {
-                let mut dal = DAL {
-                    file,
-                    page_size: options.page_size,
-                    min_fill_percent: options.min_fill_percent,
-                    max_fill_percent: options.max_fill_percent,
-                    meta: None,
-                    freelist: None,
-                };
-
-                match dal.read_meta() {
-                    Ok(meta) => {
-                        dal.meta = Some(meta);
-                    }
-                    Err(error) => {
-                        return Err(error);
-                    }
-                }
-                
-                match dal.read_freelist() {
-                    Ok(freelist) => {
-                        dal.freelist = Some(freelist);
-                    }
-                    Err(error) => {
-                        return Err(error);
-                    }
+        match dal.meta {
+            Some(ref mut meta) => {
+                meta.freelist_page = page_id;
+            }
+            None => {
+                return Err(CustomError::new("Meta not created correctly".to_string()));
+            }
+        }
+
+        Ok(dal)
+    }
+
+    /// Opens the file at `path` without reading its meta page and freelist, which are left unset.
+    pub(crate) fn open_file(path: &Path, options: &Options, write: bool, metrics: Arc<Metrics>) -> Result<DAL, CustomError> {
+        match File::options().read(true).write(write).open(path) {
+            Ok(file) => Ok(DAL {
+                file,
+                page_size: options.page_size,
+                min_fill_percent: options.min_fill_percent,
+                max_fill_percent: options.max_fill_percent,
+                meta: None,
+                freelist: None,
+                page_txids: None,
+                metrics,
+            }),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+    }
+
+    /// Flushes everything written so far to the disk.
+    pub(crate) fn sync(&self) -> Result<(), CustomError> {
+        match self.file.sync_all() {
+            Ok(()) => Ok(()),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+    }
+
+    /// Cuts off the pages past `max_page`, which `Freelist::trim` took off the freelist.
+    pub(crate) fn truncate(&self) -> Result<(), CustomError> {
+        let size = match self.freelist {
+            Some(ref freelist) => (freelist.max_page + 1) * self.page_size as u64,
+            None => {
+                return Err(CustomError::new("Freelist not initialized".to_string()));
+            }
+        };
+
+        match self.file_size() {
+            Ok(file_size) if file_size > size => {
+                match self.file.set_len(size) {
+                    Ok(()) => Ok(()),
+                    Err(error) => Err(CustomError::new(error.to_string()))
                 }
+            }
+            Ok(_) => Ok(()),
+            Err(error) => Err(error)
+        }
+    }
+
+    pub(crate) fn file_size(&self) -> Result<u64, CustomError> {
+        match self.file.metadata() {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(error) => Err(CustomError::new(error.to_string()))
+        }
+    }
+
+    /// The number of whole pages in the file, and the number of bytes left over after them.
+    pub(crate) fn file_pages(&self) -> Result<(u64, u64), CustomError> {
+        match self.file_size() {
+            Ok(size) => Ok((size / self.page_size as u64, size % self.page_size as u64)),
+            Err(error) => Err(error)
+        }
+    }
 
-                Ok(dal)
+    pub(crate) fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Rereads meta and freelist from disk, dropping in-memory changes of a transaction that never committed.
+    pub(crate) fn reload(&mut self) -> Result<(), CustomError> {
+        match self.read_meta() {
+            Ok(meta) => {
+                self.meta = Some(meta);
             }
             Err(error) => {
-                return Err(CustomError::new(error.to_string()));
+                return Err(error);
             }
         }
+
+        match self.read_freelist() {
+            Ok(freelist) => {
+                self.freelist = Some(freelist);
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        Ok(())
     }
 
     pub fn get_next_page(&mut self) -> Result<u64, CustomError> {
@@ -170,42 +444,67 @@ impl DAL {
         }
     }
 
+    /// Reads the freelist page of meta and the overflow pages chained after it.
     fn read_freelist(&self) -> Result<Freelist, CustomError> {
-        match self.meta {
-            Some(ref meta) => {
-                match self.read_page(meta.freelist_page) {
-                    Ok(page) => Ok(Freelist::deserialize(page.data)),
-                    Err(error) => Err(error)
-                }
+        let freelist_page = match self.meta {
+            Some(ref meta) => meta.freelist_page,
+            None => {
+                return Err(CustomError::new("Meta not initialized before accessing Freelist".to_string()));
             }
-            None => Err(CustomError::new("Meta not initialized before accessing Freelist".to_string()))
-        }
-    }
+        };
+        let (mut freelist, mut next_page) = match self.read_page(freelist_page) {
+            Ok(page) => Freelist::deserialize(page.data),
+            Err(error) => {
+                return Err(error);
+            }
+        };
 
-    pub fn write_freelist(&self) -> Result<Page, CustomError> {
-        let mut page = self.allocate_empty_page();
-        match self.meta.as_ref() {
-            Some(meta) => {
-                page.id = meta.freelist_page;
-                
-                match self.freelist.as_ref() {
-                    Some(freelist) => {
-                        page.data = freelist.serialize();
-                    }
-                    None => {
-                        return Err(CustomError::new("Freelist not initialized".to_string()));
-                    }
+        while let Some(page_id) = next_page {
+            if page_id == freelist_page || page_id > freelist.max_page || freelist.overflow_pages.contains(&page_id) {
+                return Err(CustomError::new(format!("Freelist overflow page {} is not a valid page", page_id)));
+            }
+            next_page = match self.read_page(page_id) {
+                Ok(page) => freelist.deserialize_overflow(page_id, page.data),
+                Err(error) => {
+                    return Err(error);
                 }
+            };
+        }
 
-                match self.write_page(&page) {
-                    Ok(()) => Ok(page),
-                    Err(error) => Err(error)
-                }
-            }
+        Ok(freelist)
+    }
+
+    /// Writes the freelist to the freelist page of meta, chaining as many overflow pages after it as the released
+    /// pages need.
+    pub fn write_freelist(&mut self) -> Result<(), CustomError> {
+        let freelist_page = match self.meta {
+            Some(ref meta) => meta.freelist_page,
             None => {
                 return Err(CustomError::new("Meta not initialized before writing Freelist".to_string()));
             }
+        };
+        let pages = match self.freelist {
+            Some(ref mut freelist) => {
+                freelist.fit_overflow_pages();
+                let mut page_ids = vec![freelist_page];
+                page_ids.extend(freelist.overflow_pages.iter());
+                page_ids.into_iter().zip(freelist.serialize()).map(|(id, data)| Page { id, data }).collect::<Vec<Page>>()
+            }
+            None => {
+                return Err(CustomError::new("Freelist not initialized".to_string()));
+            }
+        };
+
+        for page in pages.iter() {
+            match self.write_page(page) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(error);
+                }
+            }
         }
+
+        Ok(())
     }
 
     fn allocate_empty_page(&self) -> Page {
@@ -225,7 +524,10 @@ impl DAL {
                 match file.seek(SeekFrom::Start(offset)) {
                     Ok(_) => {
                         match file.read_exact(&mut page.data) {
-                            Ok(()) => Ok(page),
+                            Ok(()) => {
+                                self.metrics.page_read(page_id);
+                                Ok(page)
+                            }
                             Err(error) => {
                                 Err(CustomError::new(error.to_string()))
                             }
@@ -246,7 +548,10 @@ impl DAL {
                 match file.seek(SeekFrom::Start(offset)) {
                     Ok(_) => {
                         match file.write_all(&page.data) {
-                            Ok(()) => Ok(()),
+                            Ok(()) => {
+                                self.metrics.page_written(page.id, page.data.len());
+                                Ok(())
+                            }
                             Err(error) => Err(CustomError::new(error.to_string()))
                         }
                     }
@@ -288,15 +593,12 @@ impl DAL {
     pub fn write_node(&mut self, node: &mut Node) -> Result<(), CustomError> {
         let mut page = self.allocate_empty_page();
         if node.page_id == u64::MAX {
-            let page_id: u64;
-            match self.get_next_page() {
-                Ok(_page_id) => {
-                    page_id = _page_id;
-                }
+            let page_id = match self.get_next_page() {
+                Ok(page_id) => page_id,
                 Err(error) => {
                     return Err(error);
                 }
-            }
+            };
             page.id = page_id;
             node.page_id = page_id;
         } else {
@@ -345,9 +647,28 @@ impl DAL {
 
 }
 
+/// Whether `file` is still the file at `path`.
+#[cfg(unix)]
+fn is_file_at(file: &File, path: &Path) -> Result<bool, CustomError> {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        (Err(error), _) | (_, Err(error)) => Err(CustomError::new(error.to_string()))
+    }
+}
+
+/// Whether `file` is still the file at `path`. Elsewhere a file cannot be renamed over while it is open.
+#[cfg(not(unix))]
+fn is_file_at(_file: &File, _path: &Path) -> Result<bool, CustomError> {
+    Ok(true)
+}
+
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::{Options, DAL, DEFAULT_OPTIONS};
+    use crate::test_util::new_options;
     use std::{fs, path::Path};
 
 
@@ -357,7 +678,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_dal_test_internal"
+            path: "./db_dal_test_internal",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -384,4 +706,18 @@ mod tests {
         }
 
     }
+
+    #[test]
+    fn new_file_keeps_the_root_collection_page() {
+        let options = new_options("./db_dal_test_internal_2");
+
+        drop(DAL::new_dal(options.clone()).unwrap());
+
+        // The freelist on disk has to know about the page of the root collection, or it is handed out again.
+        let mut dal = DAL::new_dal(options).unwrap();
+        let root = dal.meta.as_ref().unwrap().root;
+        assert_eq!(root, 2);
+        assert_eq!(dal.freelist.as_ref().unwrap().max_page, root);
+        assert_ne!(dal.get_next_page().unwrap(), root);
+    }
 }
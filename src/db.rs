@@ -1,40 +1,625 @@
-use std::sync::RwLock;
+use std::{fs, io::{Read, Write}, ops::Deref, path::{Path, PathBuf}, panic::{self, AssertUnwindSafe}, sync::{mpsc::{self, Sender}, Arc, Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard}, thread::{self, ThreadId}, time::{Duration, Instant}};
 
-use crate::{dal::{Options, DAL}, tx::{Tx, TxMut}, error::CustomError};
+use crate::{archive::{self, Archive, RestorePoint}, backup::{self, BackupReport}, check::{self, CheckReport}, collection::CollectionStats, compact::{self, CompactReport}, metrics::{Metrics, MetricsSnapshot}, salvage::{self, SalvageReport}, dal::{Options, DAL}, tx::{OwnedTx, OwnedTxMut, ReadTx, Tx, TxMut}, error::CustomError, lock::TxLock};
 
+type BatchFn = Box<dyn Fn(&mut TxMut) -> Result<(), CustomError> + Send>;
+
+struct BatchCall {
+    f: BatchFn,
+    result: Sender<Result<(), CustomError>>,
+    /// Thread of the caller.
+    thread: ThreadId,
+}
+
+/// The database a transaction runs against, either borrowed or shared through an `Arc` for owned transactions.
+pub(crate) enum DbRef<'a> {
+    Borrowed(&'a DB),
+    Owned(Arc<DB>),
+}
+
+impl DbRef<'_> {
+    /// Transactions borrowing the database are bound to the thread that started them.
+    fn thread(&self) -> Option<ThreadId> {
+        match self {
+            DbRef::Borrowed(_) => Some(thread::current().id()),
+            DbRef::Owned(_) => None
+        }
+    }
+}
+
+impl Deref for DbRef<'_> {
+    type Target = DB;
+
+    fn deref(&self) -> &DB {
+        match self {
+            DbRef::Borrowed(db) => db,
+            DbRef::Owned(db) => db
+        }
+    }
+}
+
+/// Size of the database file and of everything in it, returned by `DB::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DbStats {
+    pub page_size: u64,
+    pub file_size: u64,
+    /// Highest page id handed out so far.
+    pub max_page: u64,
+    /// Number of pages on the freelist, waiting to be reused.
+    pub free_pages: u64,
+    /// Number of collections, at any depth.
+    pub collections: u64,
+    /// Totals over the trees of every collection, with the depth of the deepest one. The trees holding the
+    /// collection entries themselves are not included.
+    pub trees: CollectionStats,
+}
 
 pub struct DB {
     pub dal: RwLock<DAL>,
+    tx_lock: Arc<TxLock>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) archive: Option<Mutex<Archive>>,
+
+    options: Options,
+    max_batch_size: usize,
+    max_batch_delay: Duration,
+    batch: Mutex<Option<Vec<BatchCall>>>,
+    batch_full: Condvar,
 }
 
 impl DB {
 
     pub fn open(options: Options) -> Result<DB, CustomError> {
-        match DAL::new_dal(options) {
-            Ok(dal) => Ok(DB {
-                dal: RwLock::new(dal),
-            }),
+        let max_batch_size = options.max_batch_size;
+        let max_batch_delay = options.max_batch_delay;
+
+        let dal = match DAL::new_dal(options.clone()) {
+            Ok(dal) => dal,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        let archive = match options.archive_dir {
+            // A read-only database has no commits to archive.
+            Some(_) if options.read_only => None,
+            Some(dir) => match Archive::open(Path::new(dir), &dal) {
+                Ok(archive) => Some(Mutex::new(archive)),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+            None => None
+        };
+
+        Ok(DB {
+            metrics: dal.metrics.clone(),
+            dal: RwLock::new(dal),
+            tx_lock: Arc::new(TxLock::new()),
+            archive,
+            options,
+            max_batch_size,
+            max_batch_delay,
+            batch: Mutex::new(None),
+            batch_full: Condvar::new(),
+        })
+    }
+
+    /// Starts a read-only transaction, waiting for a running write transaction to finish.
+    pub fn read_tx(&self) -> Result<Tx<'_>, CustomError> {
+        Self::begin_read(DbRef::Borrowed(self), None)
+    }
+
+    /// Starts a read-only transaction or fails with a busy error if one cannot be started right away.
+    pub fn try_read_tx(&self) -> Result<Tx<'_>, CustomError> {
+        Self::begin_read(DbRef::Borrowed(self), Some(Instant::now()))
+    }
+
+    /// Starts a read-only transaction or fails with a busy error if one cannot be started within `timeout`.
+    pub fn read_tx_timeout(&self, timeout: Duration) -> Result<Tx<'_>, CustomError> {
+        Self::begin_read(DbRef::Borrowed(self), Some(Instant::now() + timeout))
+    }
+
+    /// Starts a read-only transaction holding its own reference to the database, so it is `'static` and `Send`.
+    /// Owned transactions are not bound to a thread and are not checked for deadlocks.
+    pub fn read_tx_owned(self: &Arc<Self>) -> Result<OwnedTx, CustomError> {
+        Self::begin_read(DbRef::Owned(Arc::clone(self)), None)
+    }
+
+    /// Starts a read-write transaction, waiting for running transactions to finish.
+    pub fn write_tx(&self) -> Result<TxMut<'_>, CustomError> {
+        Self::begin_write(DbRef::Borrowed(self), None)
+    }
+
+    /// Starts a read-write transaction or fails with a busy error if one cannot be started right away.
+    pub fn try_write_tx(&self) -> Result<TxMut<'_>, CustomError> {
+        Self::begin_write(DbRef::Borrowed(self), Some(Instant::now()))
+    }
+
+    /// Starts a read-write transaction or fails with a busy error if one cannot be started within `timeout`.
+    pub fn write_tx_timeout(&self, timeout: Duration) -> Result<TxMut<'_>, CustomError> {
+        Self::begin_write(DbRef::Borrowed(self), Some(Instant::now() + timeout))
+    }
+
+    /// Starts a read-write transaction holding its own reference to the database, so it is `'static` and `Send`.
+    /// Owned transactions are not bound to a thread and are not checked for deadlocks.
+    pub fn write_tx_owned(self: &Arc<Self>) -> Result<OwnedTxMut, CustomError> {
+        Self::begin_write(DbRef::Owned(Arc::clone(self)), None)
+    }
+
+    pub(crate) fn read_dal(&self) -> RwLockReadGuard<'_, DAL> {
+        self.dal.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn write_dal(&self) -> RwLockWriteGuard<'_, DAL> {
+        self.dal.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn begin_read(db: DbRef<'_>, deadline: Option<Instant>) -> Result<Tx<'_>, CustomError> {
+        loop {
+            let lease = match db.tx_lock.read(deadline, db.thread()) {
+                Ok(lease) => lease,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+
+            if !db.dal.is_poisoned() {
+                return Ok(Tx::new(db, lease));
+            }
+
+            // A panic interrupted a write to the DAL; starting a write transaction restores the last committed state.
+            drop(lease);
+            match Self::begin_write(DbRef::Borrowed(&db), deadline) {
+                Ok(tx) => drop(tx),
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    fn begin_write(db: DbRef<'_>, deadline: Option<Instant>) -> Result<TxMut<'_>, CustomError> {
+        if db.options.read_only {
+            return Err(CustomError::new("Database is opened read-only".to_string()));
+        }
+
+        let lease = match db.tx_lock.write(deadline, db.thread()) {
+            Ok(lease) => lease,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        match db.recover_dal() {
+            Ok(()) => Ok(TxMut::new(db, lease)),
             Err(error) => Err(error)
         }
     }
 
-    pub fn read_tx(&self) -> Tx {
-        Tx::new(self)
+    /// Called holding the write lease, before trusting the DAL after a panic interrupted a write to it.
+    fn recover_dal(&self) -> Result<(), CustomError> {
+        if self.dal.is_poisoned() {
+            // Meta and freelist may have been left half updated, so they are reread from disk before
+            // the lock is trusted again.
+            let reloaded = self.write_dal().reload();
+            match reloaded {
+                Ok(()) => {
+                    self.dal.clear_poison();
+                }
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn write_tx(&self) -> TxMut {
-        TxMut::new(self)
+    /// Runs `f` inside a read-write transaction. The transaction is committed when `f` returns `Ok`
+    /// and rolled back when it returns `Err` or panics, in which case the panic is resumed afterwards.
+    pub fn update<T, F>(&self, f: F) -> Result<T, CustomError>
+    where
+        F: FnOnce(&mut TxMut) -> Result<T, CustomError>,
+    {
+        let mut tx = match self.write_tx() {
+            Ok(tx) => tx,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut tx))) {
+            Ok(Ok(value)) => {
+                match tx.commit() {
+                    Ok(()) => Ok(value),
+                    Err(error) => Err(error)
+                }
+            }
+            Ok(Err(error)) => {
+                match tx.rollback() {
+                    Ok(()) => Err(error),
+                    Err(rollback_error) => Err(rollback_error)
+                }
+            }
+            Err(payload) => {
+                let _ = tx.rollback();
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    /// Runs `f` as part of a read-write transaction shared with other concurrent `batch` callers,
+    /// so that many small writers pay for a single commit. Calls are coalesced until
+    /// `max_batch_size` calls have joined or `max_batch_delay` has passed.
+    ///
+    /// When `f` fails, the shared transaction is rolled back and `f` is retried in a transaction of
+    /// its own, so `f` may run more than once and must be idempotent. A call that panics is dropped
+    /// from the batch and its caller receives an error. A thread holding a transaction gets a
+    /// deadlock error, as the batch would wait for that transaction to end.
+    pub fn batch<F>(&self, f: F) -> Result<(), CustomError>
+    where
+        F: Fn(&mut TxMut) -> Result<(), CustomError> + Send + 'static,
+    {
+        // The batch may be run by another thread, which cannot see that this one is waiting for it.
+        match self.tx_lock.check_write(thread::current().id()) {
+            Ok(()) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel();
+
+        let mut pending = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+        let is_leader = pending.is_none();
+        let calls = pending.get_or_insert_with(Vec::new);
+        calls.push(BatchCall {
+            f: Box::new(f),
+            result: sender,
+            thread: thread::current().id(),
+        });
+
+        if is_leader {
+            // The first caller of a batch waits for others to join and then runs the batch itself.
+            let deadline = Instant::now() + self.max_batch_delay;
+            loop {
+                let now = Instant::now();
+                let calls_count = pending.as_ref().map_or(0, |calls| calls.len());
+                if calls_count >= self.max_batch_size || now >= deadline {
+                    break;
+                }
+
+                pending = match self.batch_full.wait_timeout(pending, deadline - now) {
+                    Ok((pending, _)) => pending,
+                    Err(error) => error.into_inner().0
+                };
+            }
+
+            let calls = pending.take().unwrap_or_default();
+            drop(pending);
+
+            self.run_batch(calls);
+        } else {
+            if calls.len() >= self.max_batch_size {
+                self.batch_full.notify_all();
+            }
+            drop(pending);
+        }
+
+        match receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Err(CustomError::new("Batch call panicked before completing".to_string()))
+        }
+    }
+
+    fn run_batch(&self, mut calls: Vec<BatchCall>) {
+        while !calls.is_empty() {
+            let mut tx = match self.write_tx() {
+                Ok(tx) => tx,
+                Err(error) => {
+                    // The transaction was begun on this thread, so only its own call gets the error. The others
+                    // are run on their own.
+                    let current = thread::current().id();
+                    for call in calls.drain(0..) {
+                        if call.thread == current {
+                            let _ = call.result.send(Err(error.clone()));
+                        } else {
+                            self.run_alone(call);
+                        }
+                    }
+                    return;
+                }
+            };
+
+            let mut failed = None;
+            for (index, call) in calls.iter().enumerate() {
+                match panic::catch_unwind(AssertUnwindSafe(|| (call.f)(&mut tx))) {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => {
+                        failed = Some((index, false));
+                        break;
+                    }
+                    Err(_) => {
+                        failed = Some((index, true));
+                        break;
+                    }
+                }
+            }
+
+            match failed {
+                None => {
+                    // No call is to blame for a panic while committing, so every caller gets an error.
+                    let result = match panic::catch_unwind(AssertUnwindSafe(|| tx.commit())) {
+                        Ok(result) => result,
+                        Err(_) => Err(CustomError::new("Batch transaction panicked while committing".to_string()))
+                    };
+                    for call in calls.drain(0..) {
+                        let _ = call.result.send(result.clone());
+                    }
+                }
+                Some((index, panicked)) => {
+                    let _ = tx.rollback();
+                    let call = calls.remove(index);
+                    // The caller of a call that panicked gets an error once its sender is dropped.
+                    if !panicked {
+                        self.run_alone(call);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs a call taken out of a batch in a transaction of its own.
+    fn run_alone(&self, call: BatchCall) {
+        let solo = panic::catch_unwind(AssertUnwindSafe(|| self.update(|tx| (call.f)(tx))));
+        if let Ok(result) = solo {
+            let _ = call.result.send(result);
+        }
+    }
+
+    /// Checks the integrity of the database file: the meta page, the freelist and every collection tree, down to
+    /// the order of the keys in each node. Runs inside a read-only transaction, so it sees the last committed state.
+    pub fn check(&self) -> Result<CheckReport, CustomError> {
+        let tx = match self.read_tx() {
+            Ok(tx) => tx,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        check::check(&tx)
+    }
+
+    /// Counters of page reads and writes, commits, rollbacks and tree rebalancing since the database was opened.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Measures the database as of the last commit, reading every node of every collection.
+    pub fn stats(&self) -> Result<DbStats, CustomError> {
+        let tx = match self.read_tx() {
+            Ok(tx) => tx,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        let mut stats = {
+            let dal = tx.dal();
+            let file_size = match dal.file_size() {
+                Ok(file_size) => file_size,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            match dal.freelist {
+                Some(ref freelist) => DbStats {
+                    page_size: dal.page_size() as u64,
+                    file_size,
+                    max_page: freelist.max_page,
+                    free_pages: freelist.released_pages.len() as u64,
+                    ..DbStats::default()
+                },
+                None => {
+                    return Err(CustomError::new("Freelist not initialized".to_string()));
+                }
+            }
+        };
+
+        let mut to_visit = match tx.get_root_collection() {
+            Ok(root_collection) => vec![root_collection],
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        while let Some(owner) = to_visit.pop() {
+            let sub_collections = match owner.sub_collections(&tx) {
+                Ok(sub_collections) => sub_collections,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+            for collection in sub_collections {
+                match collection.stats(&tx) {
+                    Ok(collection_stats) => stats.trees.merge(&collection_stats),
+                    Err(error) => {
+                        return Err(error);
+                    }
+                }
+                stats.collections += 1;
+                to_visit.push(collection);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Copies every collection into a new database file at `path`, which must not exist yet. The copy holds no free
+    /// pages and its nodes are packed as full as `max_fill_percent` allows. It is taken in a single read-only
+    /// transaction, so writers wait until it is done.
+    pub fn compact_to<P: AsRef<Path>>(&self, path: P) -> Result<CompactReport, CustomError> {
+        let tx = match self.read_tx() {
+            Ok(tx) => tx,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        compact::compact(&tx, path.as_ref(), &self.options, Arc::new(Metrics::new(None)))
+    }
+
+    /// Compacts the database file in place: it is copied as by `compact_to` next to the original, which is then
+    /// replaced by renaming the copy over it. A crash leaves either the old or the new file in place. Every other
+    /// transaction waits until it is done.
+    pub fn compact(&self) -> Result<CompactReport, CustomError> {
+        if self.options.read_only {
+            return Err(CustomError::new("Database is opened read-only".to_string()));
+        }
+
+        let lease = match self.tx_lock.write(None, Some(thread::current().id())) {
+            Ok(lease) => lease,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        match self.recover_dal() {
+            Ok(()) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        // Only reads, but under the write lease so nothing commits while the files are swapped.
+        let tx = Tx::new(DbRef::Borrowed(self), lease);
+
+        let path = Path::new(self.options.path);
+        let copy_path = PathBuf::from(format!("{}.compact", self.options.path));
+        if copy_path.exists() {
+            // Left behind by a compaction that did not finish, the original is still in place.
+            match fs::remove_file(&copy_path) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(CustomError::new(error.to_string()));
+                }
+            }
+        }
+
+        let report = match compact::compact(&tx, &copy_path, &self.options, self.metrics.clone()) {
+            Ok(report) => report,
+            Err(error) => {
+                let _ = fs::remove_file(&copy_path);
+                return Err(error);
+            }
+        };
+        // The copy is locked before it takes the place of the original, so an open waiting for the lock on the
+        // original cannot take the one of the copy first.
+        let mut dal = match DAL::open_locked(&copy_path, &self.options, self.metrics.clone()) {
+            Ok(dal) => dal,
+            Err(error) => {
+                let _ = fs::remove_file(&copy_path);
+                return Err(error);
+            }
+        };
+        match fs::rename(&copy_path, path) {
+            Ok(()) => {},
+            Err(error) => {
+                let _ = fs::remove_file(&copy_path);
+                return Err(CustomError::new(error.to_string()));
+            }
+        }
+        compact::sync_directory(path);
+        // Every page moved, so backups since any txid before the compaction have to be full ones. The copy is in
+        // place either way, so it is used even when they cannot be started over.
+        let tracked = dal.track_page_txids(path, true);
+        *self.write_dal() = dal;
+        match tracked {
+            Ok(()) => {},
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        // Diffs of later commits have to start from the moved pages.
+        if let Some(ref archive) = self.archive {
+            let dal = self.read_dal();
+            match archive.lock().unwrap_or_else(PoisonError::into_inner).snapshot(&dal) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        drop(tx);
+        Ok(report)
+    }
+
+    /// Recovers what it can of a damaged database file at `source` into a new database at `options.path`, which
+    /// is opened and returned together with a report of what was lost. See `salvage::LOST_AND_FOUND` for items
+    /// whose collection could not be found.
+    pub fn salvage<P: AsRef<Path>>(source: P, options: Options) -> Result<(DB, SalvageReport), CustomError> {
+        salvage::salvage(source.as_ref(), options)
+    }
+
+    /// Writes the pages changed by commits after `since_txid` to `writer`, so a chain of backups only has to copy
+    /// what changed since the one before. Pass the `txid` of the previous backup's report as `since_txid`, or 0 for a
+    /// full backup. A full backup is also written when the changes since `since_txid` are no longer known, for
+    /// example after `compact`. The backup is taken in a single read-only transaction.
+    pub fn backup_incremental(&self, since_txid: u64, writer: &mut impl Write) -> Result<BackupReport, CustomError> {
+        let tx = match self.read_tx() {
+            Ok(tx) => tx,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        backup::backup_incremental(&tx, since_txid, writer)
+    }
+
+    /// Rebuilds a database file at `path`, which must not exist yet, from a full backup followed by the incremental
+    /// ones taken after it, in order. Returns the txid the database is restored to.
+    pub fn restore<P: AsRef<Path>, R: Read>(path: P, backups: impl IntoIterator<Item = R>) -> Result<u64, CustomError> {
+        backup::restore(path.as_ref(), backups)
+    }
+
+    /// Rebuilds the database as it was at `point`, a txid or a time, into a new file at `path` from the commits
+    /// archived in `archive_dir`, see `Options::archive_dir`. Returns the txid the database is restored to. The
+    /// restored database has to be archived in another directory, the old one holds commits past it.
+    pub fn restore_to<A: AsRef<Path>, P: AsRef<Path>>(archive_dir: A, point: impl Into<RestorePoint>, path: P) -> Result<u64, CustomError> {
+        archive::restore_to(archive_dir.as_ref(), point.into(), path.as_ref())
+    }
+
+    /// Runs `f` inside a read-only transaction which is closed once `f` returns.
+    pub fn view<T, F>(&self, f: F) -> Result<T, CustomError>
+    where
+        F: FnOnce(&Tx) -> Result<T, CustomError>,
+    {
+        let tx = match self.read_tx() {
+            Ok(tx) => tx,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        let result = f(&tx);
+        match tx.commit() {
+            Ok(()) => result,
+            Err(error) => Err(error)
+        }
     }
 }
 
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
-    use std::{fs, path::Path};
+    use std::{fs, io::Write, path::Path, sync::Arc, thread, time::{Duration, Instant}};
 
     use crate::dal::{Options, DEFAULT_OPTIONS};
+    use crate::test_util::{new_options, open};
+    use crate::tx::{OwnedTx, OwnedTxMut, ReadTx, TxMut};
 
     use super::DB;
+    use crate::error::CustomError;
+    use std::panic::{self, AssertUnwindSafe};
 
 
     #[test]
@@ -43,7 +628,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_test_internal_1"
+            path: "./db_test_internal_1",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -58,7 +644,7 @@ mod tests {
         let collection_name = "test_collection".to_string();
         match DB::open(options) {
             Ok(db) => {
-                let mut tx = db.write_tx();
+                let mut tx = db.write_tx().unwrap();
 
                 match tx.create_collection(collection_name) {
                     Ok(ref mut collection) => {
@@ -69,7 +655,7 @@ mod tests {
                             }
                         }
 
-                        match collection.find_mut("0".to_string(), &tx) {
+                        match collection.find("0".to_string(), &tx) {
                             Ok(Some(item)) => {
                                 assert_eq!(item.key, "0".to_string());
                                 assert_eq!(item.value, "1".as_bytes().to_owned());
@@ -99,4 +685,468 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn update_commits_on_ok() {
+        let db = open("./db_test_internal_2");
+
+        let result = db.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            collection.put("0".to_string(), "1".as_bytes().to_owned(), tx)?;
+            Ok(collection.name)
+        });
+        assert_eq!(result.unwrap(), "test_collection".to_string());
+
+        let value = db.view(|tx| {
+            match tx.get_collection("test_collection".to_string())? {
+                Some(collection) => collection.find("0".to_string(), tx),
+                None => Ok(None)
+            }
+        });
+        match value {
+            Ok(Some(item)) => {
+                assert_eq!(item.value, "1".as_bytes().to_owned());
+            }
+            Ok(None) => {
+                assert!(false, "Committed item not found");
+            }
+            Err(error) => {
+                assert!(false, "View failed with error: {:?}", error);
+            }
+        }
+    }
+
+    #[test]
+    fn update_rolls_back_on_error_and_panic() {
+        let db = open("./db_test_internal_3");
+
+        let result: Result<(), CustomError> = db.update(|tx| {
+            tx.create_collection("failed_collection".to_string())?;
+            Err(CustomError::new("abort".to_string()))
+        });
+        match result {
+            Err(error) => assert_eq!(error.message, "abort".to_string()),
+            Ok(()) => assert!(false, "Update should have failed")
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            db.update(|tx| -> Result<(), CustomError> {
+                tx.create_collection("panicked_collection".to_string())?;
+                panic!("abort");
+            })
+        }));
+        assert!(result.is_err());
+
+        let found = db.view(|tx| {
+            let failed = tx.get_collection("failed_collection".to_string())?;
+            let panicked = tx.get_collection("panicked_collection".to_string())?;
+            Ok(failed.is_some() || panicked.is_some())
+        });
+        match found {
+            Ok(found) => assert!(!found, "Rolled back collection was found"),
+            Err(error) => assert!(false, "View failed with error: {:?}", error)
+        }
+    }
+
+    #[test]
+    fn batch_from_concurrent_writers() {
+        let db = Arc::new(open("./db_test_internal_4"));
+        db.update(|tx| tx.create_collection("test_collection".to_string()).map(|_| ())).unwrap();
+
+        let mut handles = vec![];
+        for t in 0..8 {
+            let db = Arc::clone(&db);
+            handles.push(thread::spawn(move || {
+                for i in 0..20 {
+                    let key = format!("key_{}_{}", t, i);
+                    let result = db.batch(move |tx| {
+                        if key == "key_3_7" {
+                            return Err(CustomError::new("rejected".to_string()));
+                        }
+                        match tx.get_collection("test_collection".to_string())? {
+                            Some(mut collection) => collection.put(key.clone(), key.as_bytes().to_owned(), tx),
+                            None => Err(CustomError::new("Collection not found".to_string()))
+                        }
+                    });
+
+                    if t == 3 && i == 7 {
+                        assert!(result.is_err(), "Failing batch call should report its error");
+                    } else {
+                        assert!(result.is_ok(), "Batch call failed with error: {:?}", result);
+                    }
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        db.view(|tx| {
+            let collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            for t in 0..8 {
+                for i in 0..20 {
+                    let key = format!("key_{}_{}", t, i);
+                    let item = collection.find(key.clone(), tx)?;
+                    if t == 3 && i == 7 {
+                        assert!(item.is_none(), "Failed batch call was committed");
+                    } else {
+                        assert_eq!(item.map(|item| item.value), Some(key.as_bytes().to_owned()));
+                    }
+                }
+            }
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn busy_transactions_time_out() {
+        let db = Arc::new(open("./db_test_internal_5"));
+
+        let tx = db.read_tx().unwrap();
+        let db_clone = Arc::clone(&db);
+        thread::spawn(move || {
+            match db_clone.try_write_tx() {
+                Err(error) => assert!(error.is_busy(), "Unexpected error: {:?}", error),
+                Ok(_) => assert!(false, "Write transaction started while a read transaction is open")
+            }
+
+            let start = Instant::now();
+            match db_clone.write_tx_timeout(Duration::from_millis(50)) {
+                Err(error) => assert!(error.is_busy(), "Unexpected error: {:?}", error),
+                Ok(_) => assert!(false, "Write transaction started while a read transaction is open")
+            }
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            assert!(db_clone.try_read_tx().is_ok(), "Read transactions should not block each other");
+        }).join().unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.write_tx().unwrap();
+        let db_clone = Arc::clone(&db);
+        thread::spawn(move || {
+            match db_clone.read_tx_timeout(Duration::from_millis(10)) {
+                Err(error) => assert!(error.is_busy(), "Unexpected error: {:?}", error),
+                Ok(_) => assert!(false, "Read transaction started while a write transaction is open")
+            }
+        }).join().unwrap();
+        tx.commit().unwrap();
+
+        assert!(db.try_write_tx().is_ok());
+    }
+
+    #[test]
+    fn recover_after_panicking_write_transaction() {
+        let db = Arc::new(open("./db_test_internal_6"));
+
+        let db_clone = Arc::clone(&db);
+        let result = thread::spawn(move || {
+            let mut tx = db_clone.write_tx().unwrap();
+            tx.create_collection("test_collection".to_string()).unwrap();
+            panic!("abort");
+        }).join();
+        assert!(result.is_err());
+
+        let tx = db.read_tx().unwrap();
+        assert!(tx.get_collection("test_collection".to_string()).unwrap().is_none());
+        let max_page = tx.dal().freelist.as_ref().unwrap().max_page;
+        assert!(!tx.dal().freelist.as_ref().unwrap().released_pages.is_empty());
+        tx.commit().unwrap();
+
+        // Pages allocated by the panicking transaction are handed out again instead of leaking.
+        db.update(|tx| tx.create_collection("test_collection".to_string()).map(|_| ())).unwrap();
+        let tx = db.read_tx().unwrap();
+        assert!(tx.get_collection("test_collection".to_string()).unwrap().is_some());
+        assert_eq!(tx.dal().freelist.as_ref().unwrap().max_page, max_page);
+    }
+
+    #[test]
+    fn owned_transactions_move_between_threads() {
+        fn assert_send<T: Send + 'static>() {}
+        assert_send::<OwnedTx>();
+        assert_send::<OwnedTxMut>();
+
+        let db = Arc::new(open("./db_test_internal_8"));
+
+        let mut tx = db.write_tx_owned().unwrap();
+        let writer = thread::spawn(move || {
+            let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+            collection.put("key1".to_string(), "value1".as_bytes().to_owned(), &mut tx).unwrap();
+            tx
+        });
+        let tx = writer.join().unwrap();
+        tx.commit().unwrap();
+
+        struct Session {
+            tx: OwnedTx,
+        }
+
+        let session = Session {
+            tx: db.read_tx_owned().unwrap(),
+        };
+        let reader = thread::spawn(move || {
+            let collection = session.tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+            let item = collection.find("key1".to_string(), &session.tx).unwrap().unwrap();
+            session.tx.commit().unwrap();
+            item.value
+        });
+        assert_eq!(reader.join().unwrap(), "value1".as_bytes().to_owned());
+        assert!(db.try_write_tx().is_ok());
+    }
+
+    #[test]
+    fn conflicting_transactions_on_one_thread() {
+        let db = Arc::new(open("./db_test_internal_7"));
+
+        let tx = db.read_tx().unwrap();
+        match db.write_tx() {
+            Err(error) => assert!(error.is_deadlock(), "Unexpected error: {:?}", error),
+            Ok(_) => assert!(false, "Write transaction started while this thread holds a read transaction")
+        }
+        match db.update(|_| Ok(())) {
+            Err(error) => assert!(error.is_deadlock(), "Unexpected error: {:?}", error),
+            Ok(()) => assert!(false, "Update ran while this thread holds a read transaction")
+        }
+
+        // A writer waiting on another thread must not block this thread from reading again.
+        let db_clone = Arc::clone(&db);
+        let writer = thread::spawn(move || {
+            db_clone.write_tx().unwrap().commit().unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        let nested = db.read_tx().unwrap();
+        nested.commit().unwrap();
+        tx.commit().unwrap();
+        writer.join().unwrap();
+
+        let tx = db.write_tx().unwrap();
+        match db.read_tx() {
+            Err(error) => assert!(error.is_deadlock(), "Unexpected error: {:?}", error),
+            Ok(_) => assert!(false, "Read transaction started while this thread holds a write transaction")
+        }
+        match db.try_write_tx() {
+            Err(error) => assert!(error.is_deadlock(), "Unexpected error: {:?}", error),
+            Ok(_) => assert!(false, "Second write transaction started on the same thread")
+        }
+        tx.commit().unwrap();
+
+        assert!(db.write_tx().is_ok());
+    }
+
+    #[test]
+    fn database_stats() {
+        let db = open("./db_test_internal_9");
+        db.update(|tx| {
+            let mut users = tx.create_collection("users".to_string())?;
+            for i in 0..300 {
+                users.put(format!("user_{:04}", i), vec![0u8; 10], tx)?;
+            }
+            let mut tenants = tx.create_collection("tenants".to_string())?;
+            let mut acme = tenants.create_sub_collection("acme".to_string(), tx)?;
+            for i in 0..200 {
+                acme.put(format!("key_{:04}", i), vec![0u8; 10], tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        db.update(|tx| tx.delete_collection("users".to_string())).unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.page_size, 4096);
+        assert_eq!(stats.collections, 2);
+        assert_eq!(stats.trees.key_count, 200);
+        assert_eq!(stats.trees.key_bytes, 200 * 8);
+        assert_eq!(stats.trees.value_bytes, 200 * 10);
+        assert_eq!(stats.trees.depth, 2);
+        assert!(stats.free_pages > 0);
+        assert_eq!(stats.file_size, (stats.max_page + 1) * stats.page_size);
+    }
+
+    #[test]
+    fn file_locking() {
+        let options = new_options("./db_test_internal_10");
+
+        let read_only = Options {
+            read_only: true,
+            lock_timeout: Some(Duration::ZERO),
+            ..options.clone()
+        };
+        assert!(DB::open(read_only.clone()).is_err());
+        assert!(!Path::new(&options.path).exists());
+
+        // Each open takes its own lock, so a second open in the same process stands in for another process.
+        let db = DB::open(options.clone()).unwrap();
+        db.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            collection.put("key".to_string(), "value".as_bytes().to_owned(), tx)
+        }).unwrap();
+        let started = Instant::now();
+        let error = DB::open(Options {
+            lock_timeout: Some(Duration::from_millis(50)),
+            ..options.clone()
+        }).err().unwrap();
+        assert!(error.is_locked());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(DB::open(read_only.clone()).err().unwrap().is_locked());
+        drop(db);
+
+        let reader_a = DB::open(read_only.clone()).unwrap();
+        let reader_b = DB::open(read_only.clone()).unwrap();
+        let value = reader_b.view(|tx| tx.get_collection("test_collection".to_string())?.unwrap().find("key".to_string(), tx)).unwrap();
+        assert_eq!(value.unwrap().value, "value".as_bytes().to_owned());
+        assert!(reader_a.write_tx().is_err());
+        assert!(reader_a.update(|_| Ok(())).is_err());
+        assert!(reader_a.compact().is_err());
+        assert!(DB::open(Options {
+            lock_timeout: Some(Duration::ZERO),
+            ..options.clone()
+        }).err().unwrap().is_locked());
+
+        // Without a timeout the writer waits until the readers are gone.
+        let readers = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(reader_a);
+            drop(reader_b);
+        });
+        let started = Instant::now();
+        let db = DB::open(Options {
+            lock_timeout: None,
+            ..options
+        }).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        readers.join().unwrap();
+        db.update(|tx| {
+            let mut collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            collection.put("key".to_string(), "changed".as_bytes().to_owned(), tx)
+        }).unwrap();
+    }
+
+    #[test]
+    fn open_waiting_for_a_file_being_created() {
+        let source = open("./db_test_internal_13");
+        source.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            collection.put("key".to_string(), "value".as_bytes().to_owned(), tx)
+        }).unwrap();
+        drop(source);
+        let options = new_options("./db_test_internal_14");
+
+        // An empty file stands in for one another process has just created, before it took the lock.
+        fs::File::create_new(options.path).unwrap();
+        let started = Instant::now();
+        let error = DB::open(Options {
+            lock_timeout: Some(Duration::from_millis(50)),
+            ..options.clone()
+        }).err().unwrap();
+        assert!(error.is_locked());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // The open waits while the file is being written and goes ahead once it is complete.
+        let file = fs::File::options().write(true).open(options.path).unwrap();
+        file.lock().unwrap();
+        let waiting = {
+            let options = options.clone();
+            thread::spawn(move || DB::open(options))
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+        (&file).write_all(&fs::read("./db_test_internal_13").unwrap()).unwrap();
+        drop(file);
+
+        let db = waiting.join().unwrap().unwrap();
+        let value = db.view(|tx| tx.get_collection("test_collection".to_string())?.unwrap().find("key".to_string(), tx)).unwrap();
+        assert_eq!(value.unwrap().value, "value".as_bytes().to_owned());
+    }
+
+    #[test]
+    fn open_waiting_through_compaction() {
+        let options = new_options("./db_test_internal_11");
+
+        let db = DB::open(options.clone()).unwrap();
+        db.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            collection.put("key".to_string(), "value".as_bytes().to_owned(), tx)
+        }).unwrap();
+
+        let waiting_options = Options {
+            lock_timeout: Some(Duration::from_secs(10)),
+            ..options.clone()
+        };
+        let waiting = thread::spawn(move || DB::open(waiting_options));
+        thread::sleep(Duration::from_millis(100));
+
+        // The compaction lets go of the replaced file, but the open keeps waiting for the one now at the path.
+        db.compact().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+        db.update(|tx| {
+            let mut collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            collection.put("compacted".to_string(), "value".as_bytes().to_owned(), tx)
+        }).unwrap();
+        drop(db);
+
+        let db = waiting.join().unwrap().unwrap();
+        db.update(|tx| {
+            let mut collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            assert!(collection.find("compacted".to_string(), tx)?.is_some());
+            collection.put("reopened".to_string(), "value".as_bytes().to_owned(), tx)
+        }).unwrap();
+        drop(db);
+
+        let db = DB::open(options).unwrap();
+        db.view(|tx| {
+            let collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            assert!(collection.find("key".to_string(), tx)?.is_some());
+            assert!(collection.find("compacted".to_string(), tx)?.is_some());
+            assert!(collection.find("reopened".to_string(), tx)?.is_some());
+            Ok(())
+        }).unwrap();
+        assert!(db.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn batch_from_a_thread_holding_a_transaction() {
+        let options = Options {
+            max_batch_delay: Duration::from_millis(200),
+            ..new_options("./db_test_internal_12")
+        };
+
+        let db = Arc::new(DB::open(options).unwrap());
+        db.update(|tx| tx.create_collection("test_collection".to_string()).map(|_| ())).unwrap();
+        let put = |key: &'static str| {
+            move |tx: &mut TxMut| match tx.get_collection("test_collection".to_string())? {
+                Some(mut collection) => collection.put(key.to_string(), key.as_bytes().to_owned(), tx),
+                None => Err(CustomError::new("Collection not found".to_string()))
+            }
+        };
+
+        // Another thread leads the batch, which could only run once this thread's read transaction ends.
+        let leader = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || db.batch(put("leader")))
+        };
+        thread::sleep(Duration::from_millis(50));
+        let tx = db.read_tx().unwrap();
+        match db.batch(put("reader")) {
+            Ok(()) => assert!(false, "Batch from a thread holding a read transaction succeeded"),
+            Err(error) => assert!(error.is_deadlock(), "Unexpected error: {:?}", error),
+        }
+        drop(tx);
+        leader.join().unwrap().unwrap();
+
+        // Only the call that panicked fails, the others of its batch are committed.
+        let panicking = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || db.batch(|_| panic!("batch call panicked")))
+        };
+        thread::sleep(Duration::from_millis(50));
+        db.batch(put("after_panic")).unwrap();
+        assert!(panicking.join().unwrap().is_err());
+
+        db.view(|tx| {
+            let collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            assert!(collection.find("leader".to_string(), tx)?.is_some());
+            assert!(collection.find("reader".to_string(), tx)?.is_none());
+            assert!(collection.find("after_panic".to_string(), tx)?.is_some());
+            Ok(())
+        }).unwrap();
+    }
+}
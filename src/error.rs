@@ -1,13 +1,45 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    /// A transaction could not be started before its deadline because the database was in use.
+    Busy,
+    /// The calling thread holds a transaction that the requested transaction would wait on forever.
+    Deadlock,
+    /// Another process had the database file open for longer than `Options::lock_timeout`.
+    Locked,
+}
+
 #[derive(Debug, Clone)]
 pub struct CustomError {
-    pub message: String
+    pub message: String,
+    pub kind: ErrorKind,
 }
 
 impl CustomError {
 
     pub fn new(message: String) -> CustomError {
         CustomError {
-            message
+            message,
+            kind: ErrorKind::Other,
         }
     }
-}
\ No newline at end of file
+
+    pub fn with_kind(kind: ErrorKind, message: String) -> CustomError {
+        CustomError {
+            message,
+            kind
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.kind == ErrorKind::Busy
+    }
+
+    pub fn is_deadlock(&self) -> bool {
+        self.kind == ErrorKind::Deadlock
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.kind == ErrorKind::Locked
+    }
+}
@@ -1,9 +1,28 @@
+use std::collections::BTreeSet;
+
 use crate::{consts::{PAGE_ID_SIZE, PAGE_SIZE}, meta::META_PAGE_NUM};
 
+/// Released pages held by the first freelist page, after `max_page` and the count and before the next page id.
+const FIRST_PAGE_CAPACITY: usize = PAGE_SIZE / PAGE_ID_SIZE - 3;
+/// Released pages held by an overflow page, after the count and before the next page id.
+const OVERFLOW_PAGE_CAPACITY: usize = PAGE_SIZE / PAGE_ID_SIZE - 2;
+
 #[derive(Debug)]
 pub struct Freelist {
     pub max_page: u64,
-    pub released_pages: Vec<u64>,
+    /// Ordered so the lowest page is handed out first and the highest ones are trimmed.
+    pub released_pages: BTreeSet<u64>,
+    /// Pages after the first one that the freelist is written to. They are in use, not released.
+    pub overflow_pages: Vec<u64>,
+    /// Pages listed more than once by the freelist read from the file. They are released once, and reported by
+    /// `DB::check`.
+    pub duplicate_pages: Vec<u64>,
+}
+
+impl Default for Freelist {
+    fn default() -> Freelist {
+        Freelist::new()
+    }
 }
 
 impl Freelist {
@@ -11,71 +30,141 @@ impl Freelist {
     pub fn new() -> Freelist {
         Freelist {
             max_page: META_PAGE_NUM,
-            released_pages: vec![]
+            released_pages: BTreeSet::new(),
+            overflow_pages: vec![],
+            duplicate_pages: vec![]
         }
     }
 
+    /// Hands out the lowest released page, so that pages at the end of the file are reused last and can be cut
+    /// off by `trim` once they are all free.
     pub fn get_next_page(&mut self) -> u64 {
-        match self.released_pages.pop() {
-            Some(page_id) => page_id,
-            None => {
-                self.max_page += 1;
-                self.max_page
-            }
+        if let Some(page_id) = self.released_pages.pop_first() {
+            return page_id;
         }
+
+        self.max_page += 1;
+        self.max_page
     }
 
+    /// Releasing a page that is already free would hand it out twice, which debug builds catch here.
     pub fn release_page(&mut self, page_id: u64) {
-        self.released_pages.push(page_id)
+        let inserted = self.released_pages.insert(page_id);
+        debug_assert!(inserted, "Page {} released twice", page_id);
+    }
+
+    /// Takes released pages at the end of the file off the freelist by lowering `max_page`. Returns whether there
+    /// were any.
+    pub fn trim(&mut self) -> bool {
+        let max_page = self.max_page;
+        while self.released_pages.last() == Some(&self.max_page) {
+            self.released_pages.pop_last();
+            self.max_page -= 1;
+        }
+
+        self.max_page != max_page
     }
 
-    pub fn serialize(&self) -> [u8; PAGE_SIZE] {
-        let mut data: [u8; PAGE_SIZE] = [0u8; PAGE_SIZE];
+    /// Sizes `overflow_pages` for the released pages, taking the pages it needs from the freelist itself. A page
+    /// is only given back when the pages left can also hold it once released, so a freelist on the edge of a page
+    /// does not grow and shrink on every commit.
+    pub fn fit_overflow_pages(&mut self) {
+        while self.overflow_pages.len() < Freelist::overflow_pages_needed(self.released_pages.len()) {
+            let page_id = self.get_next_page();
+            self.overflow_pages.push(page_id);
+        }
+        while !self.overflow_pages.is_empty() && Freelist::overflow_pages_needed(self.released_pages.len() + 1) < self.overflow_pages.len() {
+            if let Some(page_id) = self.overflow_pages.pop() {
+                self.release_page(page_id);
+            }
+        }
+    }
 
-        let mut pos = 0;
-        data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&self.max_page.to_le_bytes());
-        pos += PAGE_ID_SIZE;
+    fn overflow_pages_needed(released_page_count: usize) -> usize {
+        if released_page_count <= FIRST_PAGE_CAPACITY {
+            return 0;
+        }
+        (released_page_count - FIRST_PAGE_CAPACITY).div_ceil(OVERFLOW_PAGE_CAPACITY)
+    }
 
-        data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&self.released_pages.len().to_le_bytes());
-        pos += PAGE_ID_SIZE;
+    /// Returns the first page followed by one page per overflow page. The first page holds `max_page`, the count
+    /// and the released pages, each page after it its count and released pages, and every page ends its ids with
+    /// the id of the next page, 0 on the last one. `fit_overflow_pages` must have been called first.
+    pub fn serialize(&self) -> Vec<[u8; PAGE_SIZE]> {
+        let mut pages = vec![];
+        let all_released_pages: Vec<u64> = self.released_pages.iter().copied().collect();
+        let (mut released_pages, mut remaining) = all_released_pages.split_at(all_released_pages.len().min(FIRST_PAGE_CAPACITY));
+
+        for i in 0..=self.overflow_pages.len() {
+            let mut data: [u8; PAGE_SIZE] = [0u8; PAGE_SIZE];
+
+            let mut pos = 0;
+            if i == 0 {
+                data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&self.max_page.to_le_bytes());
+                pos += PAGE_ID_SIZE;
+            }
 
-        for page_id in 0..self.released_pages.len() {
-            data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&self.released_pages[page_id].to_le_bytes());
+            data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&released_pages.len().to_le_bytes());
             pos += PAGE_ID_SIZE;
+
+            for page_id in released_pages {
+                data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&page_id.to_le_bytes());
+                pos += PAGE_ID_SIZE;
+            }
+
+            let next_page = self.overflow_pages.get(i).copied().unwrap_or(0);
+            data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&next_page.to_le_bytes());
+
+            pages.push(data);
+            (released_pages, remaining) = remaining.split_at(remaining.len().min(OVERFLOW_PAGE_CAPACITY));
         }
 
-        data
+        pages
     }
 
-    pub fn deserialize(buf: [u8; PAGE_SIZE]) -> Freelist {
-        let mut pos = 0;
+    /// Reads the first page of a freelist and returns it together with the overflow page after it, if any.
+    pub fn deserialize(buf: [u8; PAGE_SIZE]) -> (Freelist, Option<u64>) {
+        let mut freelist = Freelist {
+            max_page: read_u64(&buf, 0),
+            ..Freelist::new()
+        };
+        let next_page = freelist.read_released_pages(&buf, PAGE_ID_SIZE);
+        (freelist, next_page)
+    }
 
-        let mut u64_bytes = [0u8; PAGE_ID_SIZE];
-        for i in 0..PAGE_ID_SIZE {
-            u64_bytes[i] = buf[pos+i];
-        }
-        pos += PAGE_ID_SIZE;
-        let max_page = u64::from_le_bytes(u64_bytes);
+    /// Adds the released pages of the overflow page `page_id` and returns the overflow page after it, if any.
+    pub fn deserialize_overflow(&mut self, page_id: u64, buf: [u8; PAGE_SIZE]) -> Option<u64> {
+        self.overflow_pages.push(page_id);
+        self.read_released_pages(&buf, 0)
+    }
 
-        for i in 0..PAGE_ID_SIZE {
-            u64_bytes[i] = buf[pos+i];
-        }
-        pos += PAGE_ID_SIZE;
-        let released_page_count = usize::from_le_bytes(u64_bytes);
+    fn read_released_pages(&mut self, buf: &[u8; PAGE_SIZE], pos: usize) -> Option<u64> {
+        // A count that does not fit in the page is cut off at what does.
+        let released_page_count = (read_u64(buf, pos) as usize).min((PAGE_SIZE - pos) / PAGE_ID_SIZE - 1);
+        let mut pos = pos + PAGE_ID_SIZE;
 
-        let mut released_pages = vec![];
         for _ in 0..released_page_count {
-            for i in 0..PAGE_ID_SIZE {
-                u64_bytes[i] = buf[pos+i];
+            let page_id = read_u64(buf, pos);
+            if !self.released_pages.insert(page_id) {
+                self.duplicate_pages.push(page_id);
             }
             pos += PAGE_ID_SIZE;
-            released_pages.push(u64::from_le_bytes(u64_bytes));
         }
 
-        Freelist {
-            max_page,
-            released_pages
+        // Freelists written before they could span pages hold no next page, which reads as 0, and may fill their
+        // page up to the end.
+        if pos + PAGE_ID_SIZE > PAGE_SIZE {
+            return None;
+        }
+        match read_u64(buf, pos) {
+            META_PAGE_NUM => None,
+            next_page => Some(next_page)
         }
-
     }
-}
\ No newline at end of file
+}
+
+fn read_u64(buf: &[u8; PAGE_SIZE], pos: usize) -> u64 {
+    let mut u64_bytes = [0u8; PAGE_ID_SIZE];
+    u64_bytes.copy_from_slice(&buf[pos..pos+PAGE_ID_SIZE]);
+    u64::from_le_bytes(u64_bytes)
+}
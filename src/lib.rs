@@ -4,6 +4,19 @@ pub mod db;
 pub mod meta;
 pub mod freelist;
 pub mod collection;
+pub mod bucket;
 pub mod node;
 pub mod error;
 pub mod consts;
+pub mod check;
+pub mod salvage;
+pub mod metrics;
+pub mod compact;
+pub mod backup;
+pub mod archive;
+mod lock;
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
+mod test_util;
+#[cfg(feature = "async")]
+pub mod async_db;
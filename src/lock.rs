@@ -0,0 +1,172 @@
+use std::{collections::HashMap, sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError}, thread::ThreadId, time::Instant};
+
+use crate::error::{CustomError, ErrorKind};
+
+struct LockState {
+    readers: usize,
+    reader_threads: HashMap<ThreadId, usize>,
+    writer: bool,
+    writer_thread: Option<ThreadId>,
+    writers_waiting: usize,
+}
+
+/// Admits either many read transactions or a single write transaction. Unlike `RwLock`, acquiring it
+/// can give up at a deadline. Waiting writers block new readers so writers are not starved.
+///
+/// Transactions bound to a thread are tracked per thread, so a thread asking for a transaction that can
+/// only be admitted once its own transactions end gets an error instead of waiting forever. Owned
+/// transactions may move between threads and are not tracked.
+pub(crate) struct TxLock {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+/// Held by a transaction for its whole lifetime, releases its share of the `TxLock` when dropped.
+pub(crate) struct TxLease {
+    lock: Arc<TxLock>,
+    write: bool,
+    thread: Option<ThreadId>,
+}
+
+impl TxLock {
+
+    pub(crate) fn new() -> TxLock {
+        TxLock {
+            state: Mutex::new(LockState {
+                readers: 0,
+                reader_threads: HashMap::new(),
+                writer: false,
+                writer_thread: None,
+                writers_waiting: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until no write transaction is running or waiting. A `deadline` of `None` waits forever.
+    /// `thread` is the thread the transaction is bound to, if any.
+    pub(crate) fn read(self: &Arc<Self>, deadline: Option<Instant>, thread: Option<ThreadId>) -> Result<TxLease, CustomError> {
+        let mut state = self.lock_state();
+        if thread.is_some() && state.writer_thread == thread {
+            return Err(CustomError::with_kind(ErrorKind::Deadlock, "Cannot start a read transaction while this thread holds a write transaction".to_string()));
+        }
+
+        // A thread already reading is admitted right away, it would otherwise wait on a writer that waits on it.
+        let is_reading = match thread {
+            Some(thread) => state.reader_threads.contains_key(&thread),
+            None => false
+        };
+        if !is_reading {
+            while state.writer || state.writers_waiting > 0 {
+                let (next_state, expired) = self.wait(state, deadline);
+                state = next_state;
+                if expired {
+                    return Err(CustomError::with_kind(ErrorKind::Busy, "Timed out waiting for a read transaction".to_string()));
+                }
+            }
+        }
+
+        state.readers += 1;
+        if let Some(thread) = thread {
+            *state.reader_threads.entry(thread).or_insert(0) += 1;
+        }
+        Ok(TxLease {
+            lock: Arc::clone(self),
+            write: false,
+            thread,
+        })
+    }
+
+    /// Waits until no other transaction is running. A `deadline` of `None` waits forever.
+    /// `thread` is the thread the transaction is bound to, if any.
+    pub(crate) fn write(self: &Arc<Self>, deadline: Option<Instant>, thread: Option<ThreadId>) -> Result<TxLease, CustomError> {
+        let mut state = self.lock_state();
+        if let Some(thread) = thread {
+            match TxLock::check_writer(&state, thread) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        state.writers_waiting += 1;
+        while state.writer || state.readers > 0 {
+            let (next_state, expired) = self.wait(state, deadline);
+            state = next_state;
+            if expired {
+                state.writers_waiting -= 1;
+                self.released.notify_all();
+                return Err(CustomError::with_kind(ErrorKind::Busy, "Timed out waiting for a write transaction".to_string()));
+            }
+        }
+
+        state.writers_waiting -= 1;
+        state.writer = true;
+        state.writer_thread = thread;
+        Ok(TxLease {
+            lock: Arc::clone(self),
+            write: true,
+            thread,
+        })
+    }
+
+    /// Fails with a deadlock error when `thread` holds a transaction, which a write transaction would wait on
+    /// wherever it is started.
+    pub(crate) fn check_write(&self, thread: ThreadId) -> Result<(), CustomError> {
+        let state = self.lock_state();
+        TxLock::check_writer(&state, thread)
+    }
+
+    fn check_writer(state: &LockState, thread: ThreadId) -> Result<(), CustomError> {
+        if state.writer_thread == Some(thread) {
+            return Err(CustomError::with_kind(ErrorKind::Deadlock, "Cannot start a write transaction while this thread holds a write transaction".to_string()));
+        }
+        if state.reader_threads.contains_key(&thread) {
+            return Err(CustomError::with_kind(ErrorKind::Deadlock, "Cannot start a write transaction while this thread holds a read transaction".to_string()));
+        }
+        Ok(())
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, LockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, LockState>, deadline: Option<Instant>) -> (MutexGuard<'a, LockState>, bool) {
+        match deadline {
+            None => (self.released.wait(state).unwrap_or_else(PoisonError::into_inner), false),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return (state, true);
+                }
+
+                match self.released.wait_timeout(state, deadline - now) {
+                    Ok((state, _)) => (state, false),
+                    Err(error) => (error.into_inner().0, false)
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TxLease {
+    fn drop(&mut self) {
+        let mut state = self.lock.lock_state();
+        if self.write {
+            state.writer = false;
+            state.writer_thread = None;
+        } else {
+            state.readers -= 1;
+            if let Some(thread) = self.thread {
+                if let Some(count) = state.reader_threads.get_mut(&thread) {
+                    *count -= 1;
+                    if *count == 0 {
+                        state.reader_threads.remove(&thread);
+                    }
+                }
+            }
+        }
+        self.lock.released.notify_all();
+    }
+}
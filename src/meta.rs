@@ -6,6 +6,14 @@ pub const META_PAGE_NUM: u64 = 0;
 pub struct Meta {
     pub root: u64,
     pub freelist_page: u64,
+    /// Id of the last committed transaction, counting up from 1. Files written before commits had ids read 0.
+    pub txid: u64,
+}
+
+impl Default for Meta {
+    fn default() -> Meta {
+        Meta::new()
+    }
 }
 
 impl Meta {
@@ -13,7 +21,8 @@ impl Meta {
     pub fn new() -> Meta {
         Meta {
             root: u64::MAX,
-            freelist_page: u64::MAX
+            freelist_page: u64::MAX,
+            txid: 0
         }
     }
 
@@ -25,6 +34,9 @@ impl Meta {
         pos += PAGE_ID_SIZE;
 
         data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&self.freelist_page.to_le_bytes());
+        pos += PAGE_ID_SIZE;
+
+        data[pos..pos+PAGE_ID_SIZE].clone_from_slice(&self.txid.to_le_bytes());
 
         data
     }
@@ -33,21 +45,22 @@ impl Meta {
         let mut pos = 0;
 
         let mut u64_bytes: [u8;PAGE_ID_SIZE] = [0u8;PAGE_ID_SIZE];
-        for n in 0..PAGE_ID_SIZE {
-            u64_bytes[n] = buf[pos+n];
-        }
+        u64_bytes.copy_from_slice(&buf[pos..pos+PAGE_ID_SIZE]);
 
         let root = u64::from_le_bytes(u64_bytes);
         pos += PAGE_ID_SIZE;
 
-        for n in 0..PAGE_ID_SIZE {
-            u64_bytes[n] = buf[pos+n];
-        }
+        u64_bytes.copy_from_slice(&buf[pos..pos+PAGE_ID_SIZE]);
         let freelist_page = u64::from_le_bytes(u64_bytes);
+        pos += PAGE_ID_SIZE;
+
+        u64_bytes.copy_from_slice(&buf[pos..pos+PAGE_ID_SIZE]);
+        let txid = u64::from_le_bytes(u64_bytes);
 
         Meta {
             root,
-            freelist_page
+            freelist_page,
+            txid
         }
     }
 }
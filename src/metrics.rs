@@ -0,0 +1,306 @@
+use std::{fmt, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use crate::error::CustomError;
+
+/// Upper bounds of the buckets of the commit latency histogram. Commits slower than the last bound are counted in a
+/// bucket of their own.
+pub const COMMIT_LATENCY_BOUNDS: [Duration; 12] = [
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Told about every event counted in `Metrics` as it happens, set through `Options::observer`. Every method does
+/// nothing by default. They are called with the database locked, so they should return quickly.
+pub trait Observer: Send + Sync {
+    fn page_read(&self, _page_id: u64) {}
+
+    fn page_written(&self, _page_id: u64, _bytes: usize) {}
+
+    fn split(&self) {}
+
+    fn merge(&self) {}
+
+    /// An item moved through the parent into a sibling that fell under `min_fill_percent`.
+    fn rotation(&self) {}
+
+    fn commit(&self, _latency: Duration) {}
+
+    /// A write transaction ended without committing, either through `TxMut::rollback` or by being dropped.
+    fn rollback(&self) {}
+
+    /// Shrinking the file or archiving a commit failed after the commit was written, so the commit itself stands.
+    /// A later commit shrinks the file again, and the next archived commit is a full copy.
+    fn after_commit_error(&self, _error: &CustomError) {}
+}
+
+/// Counts of a `Histogram` bucket are not cumulative, each commit is counted once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub bounds: Vec<Duration>,
+    /// One count per bound, for the values up to that bound and above the previous one, followed by the count of
+    /// values above the last bound.
+    pub counts: Vec<u64>,
+    pub total: Duration,
+}
+
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            // Dividing the `Duration` itself would take the count as a `u32`.
+            count => Some(Duration::from_nanos((self.total.as_nanos() / count as u128) as u64))
+        }
+    }
+}
+
+/// Counters since the database was opened, returned by `DB::metrics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub pages_read: u64,
+    pub pages_written: u64,
+    pub bytes_written: u64,
+    pub commits: u64,
+    pub rollbacks: u64,
+    pub splits: u64,
+    pub merges: u64,
+    pub rotations: u64,
+    /// Commits that stood although shrinking the file or archiving them failed, see `Observer::after_commit_error`.
+    pub after_commit_errors: u64,
+    pub commit_latency: Histogram,
+}
+
+/// Counters shared by a `DB` and its `DAL`, updated without taking any lock.
+pub(crate) struct Metrics {
+    pages_read: AtomicU64,
+    pages_written: AtomicU64,
+    bytes_written: AtomicU64,
+    commits: AtomicU64,
+    rollbacks: AtomicU64,
+    splits: AtomicU64,
+    merges: AtomicU64,
+    rotations: AtomicU64,
+    after_commit_errors: AtomicU64,
+    commit_latency: [AtomicU64; COMMIT_LATENCY_BOUNDS.len() + 1],
+    commit_latency_total: AtomicU64,
+
+    observer: Option<Arc<dyn Observer>>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").field("snapshot", &self.snapshot()).finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+
+    pub(crate) fn new(observer: Option<Arc<dyn Observer>>) -> Metrics {
+        Metrics {
+            pages_read: AtomicU64::new(0),
+            pages_written: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            commits: AtomicU64::new(0),
+            rollbacks: AtomicU64::new(0),
+            splits: AtomicU64::new(0),
+            merges: AtomicU64::new(0),
+            rotations: AtomicU64::new(0),
+            after_commit_errors: AtomicU64::new(0),
+            commit_latency: Default::default(),
+            commit_latency_total: AtomicU64::new(0),
+            observer,
+        }
+    }
+
+    pub(crate) fn page_read(&self, page_id: u64) {
+        self.pages_read.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.page_read(page_id);
+        }
+    }
+
+    pub(crate) fn page_written(&self, page_id: u64, bytes: usize) {
+        self.pages_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.page_written(page_id, bytes);
+        }
+    }
+
+    pub(crate) fn split(&self) {
+        self.splits.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.split();
+        }
+    }
+
+    pub(crate) fn merge(&self) {
+        self.merges.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.merge();
+        }
+    }
+
+    pub(crate) fn rotation(&self) {
+        self.rotations.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.rotation();
+        }
+    }
+
+    pub(crate) fn commit(&self, latency: Duration) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+        let bucket = COMMIT_LATENCY_BOUNDS.iter().position(|bound| latency <= *bound).unwrap_or(COMMIT_LATENCY_BOUNDS.len());
+        self.commit_latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.commit_latency_total.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.commit(latency);
+        }
+    }
+
+    pub(crate) fn rollback(&self) {
+        self.rollbacks.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.rollback();
+        }
+    }
+
+    pub(crate) fn after_commit_error(&self, error: &CustomError) {
+        self.after_commit_errors.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref observer) = self.observer {
+            observer.after_commit_error(error);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            pages_read: self.pages_read.load(Ordering::Relaxed),
+            pages_written: self.pages_written.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            rollbacks: self.rollbacks.load(Ordering::Relaxed),
+            splits: self.splits.load(Ordering::Relaxed),
+            merges: self.merges.load(Ordering::Relaxed),
+            rotations: self.rotations.load(Ordering::Relaxed),
+            after_commit_errors: self.after_commit_errors.load(Ordering::Relaxed),
+            commit_latency: Histogram {
+                bounds: COMMIT_LATENCY_BOUNDS.to_vec(),
+                counts: self.commit_latency.iter().map(|count| count.load(Ordering::Relaxed)).collect(),
+                total: Duration::from_micros(self.commit_latency_total.load(Ordering::Relaxed)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+    use crate::{dal::Options, db::DB, test_util::new_options, tx::ReadTx};
+
+    use super::{Histogram, Observer, COMMIT_LATENCY_BOUNDS};
+
+    #[derive(Default)]
+    struct Counting {
+        pages_written: AtomicU64,
+        splits: AtomicU64,
+        merges: AtomicU64,
+        commits: AtomicU64,
+        rollbacks: AtomicU64,
+    }
+
+    impl Observer for Counting {
+        fn page_written(&self, _page_id: u64, _bytes: usize) {
+            self.pages_written.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn split(&self) {
+            self.splits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn merge(&self) {
+            self.merges.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn commit(&self, _latency: Duration) {
+            self.commits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn rollback(&self) {
+            self.rollbacks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn counters_and_observer() {
+        let observer = Arc::new(Counting::default());
+        let options = Options {
+            observer: Some(observer.clone()),
+            ..new_options("./db_metrics_test_internal_1")
+        };
+
+        let db = DB::open(options).unwrap();
+        db.update(|tx| {
+            let mut collection = tx.create_collection("test_collection".to_string())?;
+            for i in 0..500 {
+                collection.put(format!("key_{:04}", i), vec![0u8; 20], tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        db.update(|tx| {
+            let mut collection = tx.get_collection("test_collection".to_string())?.unwrap();
+            for i in 0..400 {
+                collection.remove(format!("key_{:04}", i), tx)?;
+            }
+            Ok(())
+        }).unwrap();
+
+        let tx = db.write_tx().unwrap();
+        tx.rollback().unwrap();
+        drop(db.write_tx().unwrap());
+
+        let metrics = db.metrics();
+        assert!(metrics.pages_read > 0);
+        assert!(metrics.pages_written > 0);
+        assert_eq!(metrics.bytes_written, metrics.pages_written * 4096);
+        assert_eq!(metrics.commits, 2);
+        assert_eq!(metrics.rollbacks, 2);
+        assert!(metrics.splits > 0);
+        assert!(metrics.merges > 0);
+        assert_eq!(metrics.commit_latency.count(), 2);
+        assert_eq!(metrics.commit_latency.counts.len(), metrics.commit_latency.bounds.len() + 1);
+        assert!(metrics.commit_latency.mean().is_some());
+
+        // Pages written while creating the file happen before the database is handed out, but are still observed.
+        assert_eq!(observer.pages_written.load(Ordering::Relaxed), metrics.pages_written);
+        assert_eq!(observer.splits.load(Ordering::Relaxed), metrics.splits);
+        assert_eq!(observer.merges.load(Ordering::Relaxed), metrics.merges);
+        assert_eq!(observer.commits.load(Ordering::Relaxed), 2);
+        assert_eq!(observer.rollbacks.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn mean_of_more_commits_than_fit_in_a_u32() {
+        let mut counts = vec![0; COMMIT_LATENCY_BOUNDS.len() + 1];
+        counts[0] = 1 << 33;
+        let histogram = Histogram {
+            bounds: COMMIT_LATENCY_BOUNDS.to_vec(),
+            counts,
+            total: Duration::from_micros(50) * (1 << 20) * (1 << 13),
+        };
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(50)));
+    }
+}
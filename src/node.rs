@@ -1,6 +1,6 @@
 use crate::consts::{PAGE_ID_SIZE, PAGE_SIZE};
 use crate::error::CustomError;
-use crate::tx::{Tx, TxMut};
+use crate::tx::{ReadTx, TxMut};
 
 #[derive(Clone, Debug)]
 pub struct Item {
@@ -37,7 +37,7 @@ impl Node
     }
 
     pub fn is_leaf(&self) -> bool {
-        self.child_nodes.len() == 0
+        self.child_nodes.is_empty()
     }
 
     pub fn new(page_id: u64, items: Vec<Item>, child_nodes: Vec<u64>) -> Node {
@@ -72,28 +72,24 @@ impl Node
         }
     }
 
-    fn get_node(&self, page_num: u64, tx: &Tx) -> Result<Node, CustomError> {
-        tx.get_node(page_num)
-    }
-
-    fn get_node_mut(&self, page_num: u64, tx: &TxMut) -> Result<Node, CustomError> {
+    fn get_node(&self, page_num: u64, tx: &impl ReadTx) -> Result<Node, CustomError> {
         tx.get_node(page_num)
     }
 
     pub fn is_over_populated(&self, tx: &TxMut) -> bool {
-        tx.dal.is_over_populated(self)
+        tx.dal().is_over_populated(self)
     }
 
     pub fn can_spare_an_element(&self, tx: &TxMut) -> bool {
-        let split_index = tx.dal.get_split_index(self);
+        let split_index = tx.dal().get_split_index(self);
         if split_index == usize::MAX {
             return false;
         }
-        return true;
+        true
     }
 
     pub fn is_under_populated(&self, tx: &TxMut) -> bool {
-        tx.dal.is_under_populated(self)
+        tx.dal().is_under_populated(self)
     }
 
     pub fn serialize(&self) -> [u8; PAGE_SIZE] {
@@ -117,13 +113,13 @@ impl Node
             let item = self.items[i].clone();
 
             if !self.is_leaf() {
-                let child_node = self.child_nodes[i].clone();
+                let child_node = self.child_nodes[i];
 
                 buf[left_pos..left_pos+PAGE_ID_SIZE].clone_from_slice(&child_node.to_le_bytes());
                 left_pos += PAGE_ID_SIZE;
             }
 
-            let key_len = item.key.as_bytes().len();
+            let key_len = item.key.len();
             let val_len = item.value.len();
 
             let offset = right_pos - key_len - val_len - 2;
@@ -137,7 +133,7 @@ impl Node
             buf[right_pos..right_pos+1].clone_from_slice(&(val_len as u8).to_le_bytes());
 
             right_pos -= key_len;
-            buf[right_pos..right_pos+key_len].clone_from_slice(&item.key.as_bytes());
+            buf[right_pos..right_pos+key_len].clone_from_slice(item.key.as_bytes());
 
             right_pos -= 1;
             buf[right_pos..right_pos+1].clone_from_slice(&(key_len as u8).to_le_bytes());
@@ -148,7 +144,7 @@ impl Node
         }
 
         if !self.is_leaf() {
-            let last_child_node = self.child_nodes[self.child_nodes.len() - 1].clone();
+            let last_child_node = self.child_nodes[self.child_nodes.len() - 1];
 
             buf[left_pos..left_pos+PAGE_ID_SIZE].clone_from_slice(&last_child_node.to_le_bytes());
         }
@@ -167,29 +163,35 @@ impl Node
         left_pos += 1;
 
         let mut u16_bytes: [u8;2] = [0u8;2];
-        for n in 0..2 {
-            u16_bytes[n] = buf[left_pos+n];
-        }
+        u16_bytes.copy_from_slice(&buf[left_pos..left_pos+2]);
         left_pos += 2;
         let item_len = u16::from_le_bytes(u16_bytes) as usize;
 
+        // Corrupted pages have to fail here rather than index out of the page.
+        let mut header_len = 2;
+        if is_leaf == 0 {
+            header_len += PAGE_ID_SIZE;
+        }
+        if left_pos + item_len * header_len + PAGE_ID_SIZE > buf.len() {
+            return Err(CustomError::new(format!("Corrupted node, {} items do not fit in a page", item_len)));
+        }
+
         for _ in 0..item_len {
             
             if is_leaf == 0 {
                 let mut u64_bytes: [u8; PAGE_ID_SIZE] = [0u8; PAGE_ID_SIZE];
-                for n in 0..PAGE_ID_SIZE {
-                    u64_bytes[n] = buf[left_pos+n];
-                }
+                u64_bytes.copy_from_slice(&buf[left_pos..left_pos+PAGE_ID_SIZE]);
                 left_pos += PAGE_ID_SIZE;
                 node.child_nodes.push(u64::from_le_bytes(u64_bytes));
             }
 
             u16_bytes = [0u8; 2];
-            for n in 0..2 {
-                u16_bytes[n] = buf[left_pos+n];
-            }
+            u16_bytes.copy_from_slice(&buf[left_pos..left_pos+2]);
             left_pos += 2;
             let mut offset = u16::from_le_bytes(u16_bytes) as usize;
+            if offset + 2 > buf.len() || offset + 2 + buf[offset] as usize > buf.len() {
+                return Err(CustomError::new(format!("Corrupted node, item offset {} is out of the page", offset)));
+            }
 
             u8_bytes = [0u8; 1];
             u8_bytes[0] = buf[offset];
@@ -211,15 +213,16 @@ impl Node
             u8_bytes[0] = buf[offset];
             offset += 1;
             let val_len = u8::from_le_bytes(u8_bytes) as usize;
+            if offset + val_len > buf.len() {
+                return Err(CustomError::new(format!("Corrupted node, value at offset {} is out of the page", offset)));
+            }
 
             node.items.push(Item::new(key, buf[offset..offset+val_len].to_vec()));
         }
 
         if is_leaf == 0 {
             let mut u64_bytes = [0u8; PAGE_ID_SIZE];
-            for n in 0..PAGE_ID_SIZE {
-                u64_bytes[n] = buf[left_pos+n];
-            }
+            u64_bytes.copy_from_slice(&buf[left_pos..left_pos+PAGE_ID_SIZE]);
             node.child_nodes.push(u64::from_le_bytes(u64_bytes));
         }
 
@@ -236,11 +239,16 @@ impl Node
         insertion_index
     }
 
+    /// Bytes item `i` takes in the serialized node: its offset, the lengths and bytes of key and value, and the
+    /// page id of the child before it in internal nodes.
     pub fn element_size(&self, i: usize) -> usize {
         let mut size = 0;
-        size += &self.items[i].key.len();
-        size += &self.items[i].value.len();
-        size += PAGE_ID_SIZE;
+        size += 2;
+        size += 1 + &self.items[i].key.len();
+        size += 1 + &self.items[i].value.len();
+        if !self.is_leaf() {
+            size += PAGE_ID_SIZE;
+        }
         size
     }
 
@@ -252,11 +260,13 @@ impl Node
             size += self.element_size(i)
         }
 
-        size += PAGE_ID_SIZE;
+        if !self.is_leaf() {
+            size += PAGE_ID_SIZE;
+        }
         size
     }
 
-    pub fn find_key(&self, key: &String, exact: bool, tx: &Tx) -> Result<(usize, Node, Vec<usize>), CustomError> {
+    pub fn find_key(&self, key: &String, exact: bool, tx: &impl ReadTx) -> Result<(usize, Node, Vec<usize>), CustomError> {
         let mut ancestors_indexes = vec![0];
         
         match Self::find_key_helper(self.clone(), key, exact, &mut ancestors_indexes, tx) {
@@ -265,7 +275,7 @@ impl Node
         }
     }
 
-    fn find_key_helper(node: Node, key: &String, exact: bool, ancestor_indexes: &mut Vec<usize>, tx: &Tx) -> Result<(usize, Node), CustomError> {
+    fn find_key_helper(node: Node, key: &String, exact: bool, ancestor_indexes: &mut Vec<usize>, tx: &impl ReadTx) -> Result<(usize, Node), CustomError> {
         let (was_found, index) = node.find_key_in_node(key);
         if was_found {
             return Ok((index, node))
@@ -287,36 +297,6 @@ impl Node
     }
 
 
-    pub fn find_key_mut(&self, key: &String, exact: bool, tx: &TxMut) -> Result<(usize, Node, Vec<usize>), CustomError> {
-        let mut ancestors_indexes = vec![0];
-        
-        match Self::find_key_helper_mut(self.clone(), key, exact, &mut ancestors_indexes, tx) {
-            Ok((index, containing_node)) => Ok((index, containing_node, ancestors_indexes)),
-            Err(error) => Err(error)
-        }
-    }
-
-    fn find_key_helper_mut(node: Node, key: &String, exact: bool, ancestor_indexes: &mut Vec<usize>, tx: &TxMut) -> Result<(usize, Node), CustomError> {
-        let (was_found, index) = node.find_key_in_node(key);
-        if was_found {
-            return Ok((index, node))
-        }
-        
-        if node.is_leaf() {
-            if exact {
-                return Ok((usize::MAX, node))
-            }
-            return Ok((index, node))
-        }
-
-        (*ancestor_indexes).push(index);
-
-        match node.get_node_mut(node.child_nodes[index], tx) {
-            Ok(next_child) => Self::find_key_helper_mut(next_child, key, exact, ancestor_indexes, tx),
-            Err(error) => Err(error)
-        }
-    }
-
     fn find_key_in_node(&self, key: &String) -> (bool, usize) {
         for (i, item) in self.items.iter().enumerate() {
             if *key == item.key {
@@ -332,7 +312,8 @@ impl Node
     }
 
     pub fn split(&mut self, node_to_split: &mut Node, node_to_split_index: usize, tx: &mut TxMut) {
-        let split_index = tx.dal.get_split_index(node_to_split); // Add split index
+        tx.metrics().split();
+        let split_index = tx.dal().get_split_index(node_to_split); // Add split index
 
         let middle_item = node_to_split.items.remove(split_index);
         
@@ -381,13 +362,13 @@ impl Node
         let mut affected_nodes = vec![];
         affected_nodes.push(index);
 
-        let mut a_node_res = self.get_node_mut(self.child_nodes[index], tx);
+        let mut a_node_res = self.get_node(self.child_nodes[index], tx);
         
         while let Ok(ref mut a_node) = a_node_res {
             if !a_node.is_leaf() {
-                let traversing_index = self.child_nodes.len() - 1;
+                let traversing_index = a_node.child_nodes.len() - 1;
                 
-                match a_node.get_node_mut(a_node.child_nodes[traversing_index], tx) {
+                match a_node.get_node(a_node.child_nodes[traversing_index], tx) {
                     Ok(node) => {
                         a_node_res = Ok(node);
                     }
@@ -453,9 +434,10 @@ impl Node
     }
 
     fn merge(&mut self, b_node: &mut Node, b_node_index: usize, tx: &mut TxMut) -> Result<(), CustomError> {
-        let mut a_node = self.get_node_mut(self.child_nodes[b_node_index-1], tx);
+        let mut a_node = self.get_node(self.child_nodes[b_node_index-1], tx);
         match a_node {
             Ok(ref mut a_node) => {
+                tx.metrics().merge();
                 let p_node_item = self.items.remove(b_node_index-1);
                 a_node.items.push(p_node_item);
 
@@ -465,9 +447,14 @@ impl Node
                     a_node.child_nodes.extend(b_node.child_nodes.drain(0..));
                 }
 
-                self.write_self_node(tx);
-                self.write_node(a_node, tx);
                 tx.delete_node(b_node);
+                // Merging two nodes that are each about half full can overflow the page, so split it again.
+                if a_node.is_over_populated(tx) {
+                    self.split(a_node, b_node_index-1, tx);
+                } else {
+                    self.write_self_node(tx);
+                    self.write_node(a_node, tx);
+                }
 
                 Ok(())
             }
@@ -477,16 +464,22 @@ impl Node
 
     pub fn rebalance_remove(&mut self, unbalanced_node: &mut Node, unbalanced_node_index: usize, tx: &mut TxMut) -> Result<(), CustomError> {
         if unbalanced_node_index != 0 {
-            let left_node = self.get_node_mut(self.child_nodes[unbalanced_node_index-1], tx);
+            let left_node = self.get_node(self.child_nodes[unbalanced_node_index-1], tx);
             match left_node {
                 Ok(mut left_node) => {
                     if left_node.can_spare_an_element(tx) {
-                        Self::rotate_right(&mut left_node, self, unbalanced_node, unbalanced_node_index);
+                        // One item may not be enough when it is smaller than what was removed.
+                        while unbalanced_node.is_under_populated(tx) && left_node.can_spare_an_element(tx) {
+                            Self::rotate_right(&mut left_node, self, unbalanced_node, unbalanced_node_index);
+                            tx.metrics().rotation();
+                        }
                         
                         self.write_self_node(tx);
                         self.write_nodes(vec![&mut left_node, unbalanced_node], tx);
                         
-                        return Ok(());
+                        if !unbalanced_node.is_under_populated(tx) {
+                            return Ok(());
+                        }
                     }
                 }
                 Err(error) => {
@@ -497,16 +490,21 @@ impl Node
         }
 
         if unbalanced_node_index != self.child_nodes.len() - 1 {
-            let right_node = self.get_node_mut(self.child_nodes[unbalanced_node_index+1], tx);
+            let right_node = self.get_node(self.child_nodes[unbalanced_node_index+1], tx);
             match right_node {
                 Ok(mut right_node) => {
                     if right_node.can_spare_an_element(tx) {
-                        Self::rotate_left(unbalanced_node, self, &mut right_node, unbalanced_node_index);
+                        while unbalanced_node.is_under_populated(tx) && right_node.can_spare_an_element(tx) {
+                            Self::rotate_left(unbalanced_node, self, &mut right_node, unbalanced_node_index);
+                            tx.metrics().rotation();
+                        }
                         
                         self.write_self_node(tx);
                         self.write_nodes(vec![unbalanced_node, &mut right_node], tx);
                         
-                        return Ok(());
+                        if !unbalanced_node.is_under_populated(tx) {
+                            return Ok(());
+                        }
                     }
                 }
                 Err(error) => {
@@ -517,7 +515,7 @@ impl Node
         }
 
         if unbalanced_node_index == 0 {
-            let mut right_node = self.get_node_mut(self.child_nodes[unbalanced_node_index+1], tx);
+            let mut right_node = self.get_node(self.child_nodes[unbalanced_node_index+1], tx);
             match right_node {
                 Ok(ref mut right_node) => {
                     return self.merge(right_node, unbalanced_node_index+1, tx);
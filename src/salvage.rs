@@ -0,0 +1,493 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, path::Path, sync::Arc};
+
+use crate::{collection::Collection, consts::{COLLECTION_SIZE, PAGE_ID_SIZE}, dal::{Options, Page, DAL}, db::DB, error::CustomError, freelist::Freelist, meta::{Meta, META_PAGE_NUM}, metrics::Metrics, node::{Item, Node}};
+
+/// Collection holding the items of pages that could not be traced back to the collection they belonged to,
+/// with a sub-collection `page_<id>` for each such tree.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// Keys that were stored on a page that could not be recovered, see `SalvageReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loss {
+    pub page_id: u64,
+    /// Path of the collection the page belonged to, separated by `/`. Empty for the directory of top-level
+    /// collections.
+    pub collection: String,
+    /// The keys lost lie strictly between these two keys, either of which is `None` when unbounded.
+    pub lower: Option<String>,
+    pub upper: Option<String>,
+    pub message: String,
+}
+
+/// Result of `DB::salvage`.
+#[derive(Debug, Clone, Default)]
+pub struct SalvageReport {
+    /// Number of whole pages in the damaged file.
+    pub pages_scanned: u64,
+    /// Bytes at the end of the damaged file that do not make up a whole page.
+    pub trailing_bytes: u64,
+    /// Whether the meta page was unusable, in which case every item ends up in `LOST_AND_FOUND`.
+    pub meta_lost: bool,
+    /// Whether the freelist was unusable, in which case items of freed pages may come back.
+    pub freelist_lost: bool,
+    /// Number of collections written to the new file, including `LOST_AND_FOUND` and its sub-collections.
+    pub collections: u64,
+    pub keys: u64,
+    /// Pages not reachable from the meta page whose items were put back into the collection they were missing
+    /// from.
+    pub orphans_restored: u64,
+    /// Pages not reachable from the meta page whose items were put into `LOST_AND_FOUND`.
+    pub orphans_unplaced: u64,
+    /// Subtrees that could not be read. Keys of a loss may have been recovered from orphan pages since.
+    pub losses: Vec<Loss>,
+}
+
+#[derive(Default)]
+struct Recovered {
+    sequence: u64,
+    items: BTreeMap<String, Vec<u8>>,
+}
+
+/// A subtree of a collection that could not be read, which orphan pages with keys in its range may fill.
+struct Hole {
+    path: Vec<String>,
+    lower: Option<String>,
+    upper: Option<String>,
+}
+
+struct Salvager {
+    pages: HashMap<u64, Node>,
+    reached: HashSet<u64>,
+    /// Subtrees found lost by the last walk.
+    lost: Vec<Hole>,
+    holes: Vec<Hole>,
+    collections: BTreeMap<Vec<String>, Recovered>,
+    report: SalvageReport,
+}
+
+/// Reads what it can of the database file at `source` and writes it to a new database at `options.path`, which
+/// must not exist yet. Collections are rebuilt by walking the trees from the meta page, skipping pages that cannot
+/// be read. Every other page of the file that decodes as a node and is not on the freelist is an orphan: its items
+/// are put back into the collection with a lost subtree covering their keys, or into `LOST_AND_FOUND` when there is
+/// no single such collection.
+pub(crate) fn salvage(source: &Path, options: Options) -> Result<(DB, SalvageReport), CustomError> {
+    if Path::new(options.path).exists() {
+        return Err(CustomError::new(format!("Salvage target already exists: {}", options.path)));
+    }
+
+    let dal = match DAL::open_file(source, &options, false, Arc::new(Metrics::new(None))) {
+        Ok(dal) => dal,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let (page_count, trailing_bytes) = match dal.file_pages() {
+        Ok(file_pages) => file_pages,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    let mut salvager = Salvager {
+        pages: HashMap::new(),
+        reached: HashSet::new(),
+        lost: vec![],
+        holes: vec![],
+        collections: BTreeMap::new(),
+        report: SalvageReport {
+            pages_scanned: page_count,
+            trailing_bytes,
+            ..SalvageReport::default()
+        },
+    };
+
+    let meta = match dal.read_page(META_PAGE_NUM) {
+        Ok(page) => Some(Meta::deserialize(page.data)),
+        Err(_) => None
+    }.filter(|meta| meta.freelist_page != META_PAGE_NUM && meta.freelist_page < page_count && meta.root != META_PAGE_NUM && meta.root < page_count && meta.root != meta.freelist_page);
+
+    let mut free = HashSet::new();
+    match meta {
+        Some(ref meta) => {
+            match read_freelist(&dal, meta.freelist_page) {
+                Some(freelist) => {
+                    free.extend(freelist.released_pages);
+                    free.extend(freelist.overflow_pages);
+                }
+                None => {
+                    salvager.report.freelist_lost = true;
+                }
+            }
+            free.insert(meta.freelist_page);
+        }
+        None => {
+            salvager.report.meta_lost = true;
+            salvager.report.freelist_lost = true;
+        }
+    }
+
+    for page_id in 1..page_count {
+        if free.contains(&page_id) {
+            continue;
+        }
+        if let Ok(page) = dal.read_page(page_id) {
+            // The first byte is 1 for leaves and 0 for internal nodes, anything else is not a node.
+            if page.data[0] > 1 {
+                continue;
+            }
+            if let Ok(node) = Node::deserialize(page.data) {
+                if node.child_nodes.iter().all(|child| *child != META_PAGE_NUM && *child < page_count) {
+                    salvager.pages.insert(page_id, node);
+                }
+            }
+        }
+    }
+
+    if let Some(ref meta) = meta {
+        salvager.walk_directory(meta.root, &[]);
+    }
+    salvager.place_orphans();
+
+    let db = match DB::open(options) {
+        Ok(db) => db,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+    let collections = std::mem::take(&mut salvager.collections);
+    let written = db.update(|tx| {
+        for (path, recovered) in collections.iter() {
+            let name = path[path.len() - 1].clone();
+            let created = match path.len() {
+                1 => tx.create_collection(name),
+                _ => match Collection::at_path(&path[..path.len() - 1], tx) {
+                    Ok(Some(mut parent)) => parent.create_sub_collection(name, tx),
+                    Ok(None) => Err(CustomError::new(format!("Parent of {} not recovered", path.join("/")))),
+                    Err(error) => Err(error)
+                }
+            };
+            let mut collection = match created {
+                Ok(collection) => collection,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+
+            for (key, value) in recovered.items.iter() {
+                match collection.put(key.clone(), value.clone(), tx) {
+                    Ok(()) => {},
+                    Err(error) => {
+                        return Err(error);
+                    }
+                }
+            }
+            if recovered.sequence > 0 {
+                match collection.set_sequence(recovered.sequence, tx) {
+                    Ok(()) => {},
+                    Err(error) => {
+                        return Err(error);
+                    }
+                }
+            }
+        }
+        Ok(())
+    });
+    match written {
+        Ok(()) => {},
+        Err(error) => {
+            return Err(error);
+        }
+    }
+
+    salvager.report.collections = collections.len() as u64;
+    salvager.report.keys = collections.values().map(|recovered| recovered.items.len() as u64).sum();
+    Ok((db, salvager.report))
+}
+
+/// Reads the freelist at `page_id` and the overflow pages chained after it, or `None` when a page count does not
+/// fit in its page or the chain leads nowhere.
+fn read_freelist(dal: &DAL, page_id: u64) -> Option<Freelist> {
+    let page = match read_freelist_page(dal, page_id, PAGE_ID_SIZE) {
+        Some(page) => page,
+        None => {
+            return None;
+        }
+    };
+    let (mut freelist, mut next_page) = Freelist::deserialize(page.data);
+
+    while let Some(overflow_page) = next_page {
+        if overflow_page == page_id || freelist.overflow_pages.contains(&overflow_page) {
+            return None;
+        }
+        next_page = match read_freelist_page(dal, overflow_page, 0) {
+            Some(page) => freelist.deserialize_overflow(overflow_page, page.data),
+            None => {
+                return None;
+            }
+        };
+    }
+
+    Some(freelist)
+}
+
+/// Reads the freelist page `page_id`, or `None` when the page count at `count_pos` does not fit in the page.
+fn read_freelist_page(dal: &DAL, page_id: u64, count_pos: usize) -> Option<Page> {
+    let page = match dal.read_page(page_id) {
+        Ok(page) => page,
+        Err(_) => {
+            return None;
+        }
+    };
+
+    let mut count_bytes = [0u8; PAGE_ID_SIZE];
+    count_bytes.copy_from_slice(&page.data[count_pos..count_pos + PAGE_ID_SIZE]);
+    let count = u64::from_le_bytes(count_bytes);
+    if count > ((page.data.len() - count_pos) / PAGE_ID_SIZE - 1) as u64 {
+        return None;
+    }
+
+    Some(page)
+}
+
+impl Salvager {
+
+    fn loss(&mut self, page_id: u64, path: &[String], lower: Option<&str>, upper: Option<&str>, message: String) {
+        self.report.losses.push(Loss {
+            page_id,
+            collection: path.join("/"),
+            lower: lower.map(str::to_string),
+            upper: upper.map(str::to_string),
+            message,
+        });
+    }
+
+    /// Recovers the collections whose entries are in the directory tree at `root`, and their sub-collections.
+    fn walk_directory(&mut self, root: u64, path: &[String]) {
+        let mut entries = vec![];
+        self.walk(root, path, None, None, &mut entries);
+        // Orphans are only matched against the trees holding keys, entries of lost collections are not rebuilt.
+        self.lost.clear();
+
+        for entry in entries {
+            let mut child_path = path.to_vec();
+            child_path.push(entry.key.clone());
+
+            // Entries of 16 bytes were written before collections could have sub-collections.
+            if entry.value.len() != COLLECTION_SIZE && entry.value.len() != 2 * PAGE_ID_SIZE {
+                self.loss(root, &child_path, None, None, format!("Collection entry of {} bytes", entry.value.len()));
+                continue;
+            }
+
+            let collection = Collection::deserialize(entry);
+            let mut items = vec![];
+            self.walk(collection.root, &child_path, None, None, &mut items);
+            let lost = std::mem::take(&mut self.lost);
+            self.holes.extend(lost);
+
+            let recovered = self.collections.entry(child_path.clone()).or_default();
+            recovered.sequence = collection.sequence();
+            recovered.items.extend(items.into_iter().map(|item| (item.key, item.value)));
+
+            self.walk_directory(collection.children(), &child_path);
+        }
+    }
+
+    /// Collects the items of the tree at `page_id` into `items`, recording a loss for each subtree that cannot be
+    /// read.
+    fn walk(&mut self, page_id: u64, path: &[String], lower: Option<&str>, upper: Option<&str>, items: &mut Vec<Item>) {
+        if page_id == u64::MAX {
+            return;
+        }
+
+        let node = match self.pages.get(&page_id) {
+            Some(node) if !self.reached.contains(&page_id) => node.clone(),
+            found => {
+                let message = match found {
+                    Some(_) => format!("Page {} is referenced more than once", page_id),
+                    None => format!("Page {} does not hold a node", page_id)
+                };
+                self.loss(page_id, path, lower, upper, message);
+                self.lost.push(Hole {
+                    path: path.to_vec(),
+                    lower: lower.map(str::to_string),
+                    upper: upper.map(str::to_string),
+                });
+                return;
+            }
+        };
+        self.reached.insert(page_id);
+
+        if node.is_leaf() {
+            items.extend(node.items);
+            return;
+        }
+        for (i, child) in node.child_nodes.iter().enumerate() {
+            let child_lower = if i == 0 { lower } else { Some(node.items[i - 1].key.as_str()) };
+            let child_upper = if i == node.items.len() { upper } else { Some(node.items[i].key.as_str()) };
+            self.walk(*child, path, child_lower, child_upper, items);
+            if i < node.items.len() {
+                items.push(node.items[i].clone());
+            }
+        }
+    }
+
+    /// Recovers the trees of nodes that are neither reachable from the meta page nor free.
+    fn place_orphans(&mut self) {
+        let orphans: HashSet<u64> = self.pages.keys().filter(|page_id| !self.reached.contains(page_id)).copied().collect();
+        let children: HashSet<u64> = orphans.iter().flat_map(|page_id| self.pages[page_id].child_nodes.clone()).collect();
+        let mut roots: Vec<u64> = orphans.iter().filter(|page_id| !children.contains(page_id)).copied().collect();
+        roots.sort();
+
+        for root in roots {
+            let reached_before = self.reached.len();
+            let losses_before = self.report.losses.len();
+            let mut items = vec![];
+            self.walk(root, &[], None, None, &mut items);
+            // Orphans are recovered as far as they go, what they point to is not known to belong to them.
+            self.report.losses.truncate(losses_before);
+            self.lost.clear();
+            if items.is_empty() {
+                continue;
+            }
+            let pages = (self.reached.len() - reached_before) as u64;
+
+            let first = items.iter().map(|item| item.key.as_str()).min().unwrap_or_default().to_string();
+            let last = items.iter().map(|item| item.key.as_str()).max().unwrap_or_default().to_string();
+            let mut fitting = self.holes.iter().filter(|hole| {
+                hole.lower.as_ref().is_none_or(|lower| *lower < first) && hole.upper.as_ref().is_none_or(|upper| last < *upper)
+            });
+            let path = match (fitting.next(), fitting.next()) {
+                (Some(hole), None) => {
+                    self.report.orphans_restored += pages;
+                    hole.path.clone()
+                }
+                _ => {
+                    self.report.orphans_unplaced += pages;
+                    self.collections.entry(vec![LOST_AND_FOUND.to_string()]).or_default();
+                    vec![LOST_AND_FOUND.to_string(), format!("page_{}", root)]
+                }
+            };
+
+            let recovered = self.collections.entry(path).or_default();
+            for item in items {
+                recovered.items.entry(item.key).or_insert(item.value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::{Seek, SeekFrom, Write}};
+
+    use crate::{consts::PAGE_SIZE, dal::{Options, DEFAULT_OPTIONS}, db::DB, test_util::{new_options, open}, tx::ReadTx};
+
+    use super::LOST_AND_FOUND;
+
+    /// Creates a database with a collection `users` of 500 keys and a collection `tenants/acme` of 50 keys, and
+    /// returns the root page of `users`, the first leaf below it and the keys kept in the root.
+    fn create_source(path: &'static str) -> (u64, u64, Vec<String>) {
+        let db = open(path);
+        db.update(|tx| {
+            let mut users = tx.create_collection("users".to_string())?;
+            for i in 0..500 {
+                users.put(format!("user_{:04}", i), format!("value_{}", i).as_bytes().to_owned(), tx)?;
+            }
+            let mut tenants = tx.create_collection("tenants".to_string())?;
+            let mut acme = tenants.create_sub_collection("acme".to_string(), tx)?;
+            for i in 0..50 {
+                acme.put(format!("key_{:04}", i), vec![1, 2, 3], tx)?;
+            }
+            acme.set_sequence(7, tx)?;
+            Ok(())
+        }).unwrap();
+
+        db.view(|tx| {
+            let users = tx.get_collection("users".to_string())?.unwrap();
+            let root = tx.get_node(users.root)?;
+            Ok((users.root, root.child_nodes[0], root.items.into_iter().map(|item| item.key).collect()))
+        }).unwrap()
+    }
+
+    fn overwrite_page(path: &str, page_id: u64, byte: u8) {
+        let mut file = fs::File::options().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(page_id * PAGE_SIZE as u64)).unwrap();
+        file.write_all(&[byte; PAGE_SIZE]).unwrap();
+    }
+
+    #[test]
+    fn lost_leaf_is_reported() {
+        let (_, leaf, _) = create_source("./db_salvage_test_internal_1");
+        overwrite_page("./db_salvage_test_internal_1", leaf, 0xff);
+
+        let (db, report) = DB::salvage("./db_salvage_test_internal_1", new_options("./db_salvage_test_internal_2")).unwrap();
+        assert!(!report.meta_lost);
+        assert_eq!(report.losses.len(), 1);
+        assert_eq!(report.losses[0].page_id, leaf);
+        assert_eq!(report.losses[0].collection, "users");
+        assert_eq!(report.losses[0].lower, None);
+        assert_eq!(report.orphans_restored + report.orphans_unplaced, 0);
+        assert!(db.check().unwrap().is_ok());
+
+        let tx = db.read_tx().unwrap();
+        let users = tx.get_collection("users".to_string()).unwrap().unwrap();
+        let upper = report.losses[0].upper.clone().unwrap();
+        assert!(users.find("user_0000".to_string(), &tx).unwrap().is_none());
+        assert!(users.find(upper.clone(), &tx).unwrap().is_some());
+        assert_eq!(users.iter(&tx).count(), 500 - upper[5..].parse::<usize>().unwrap());
+
+        let acme = tx.get_collection("tenants".to_string()).unwrap().unwrap().get_sub_collection("acme".to_string(), &tx).unwrap().unwrap();
+        assert_eq!(acme.iter(&tx).count(), 50);
+        assert_eq!(acme.sequence(), 7);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn orphans_are_put_back() {
+        let (root, _, root_keys) = create_source("./db_salvage_test_internal_3");
+        overwrite_page("./db_salvage_test_internal_3", root, 0xff);
+
+        let (db, report) = DB::salvage("./db_salvage_test_internal_3", new_options("./db_salvage_test_internal_4")).unwrap();
+        assert_eq!(report.losses.len(), 1);
+        assert_eq!(report.losses[0].page_id, root);
+        assert!(report.orphans_restored > 0);
+        assert_eq!(report.orphans_unplaced, 0);
+        // Keys kept in the lost root itself cannot come back.
+        assert_eq!(report.keys, 550 - root_keys.len() as u64);
+
+        let tx = db.read_tx().unwrap();
+        let users = tx.get_collection("users".to_string()).unwrap().unwrap();
+        let keys: Vec<String> = users.iter(&tx).map(|item| item.unwrap().key).collect();
+        let expected: Vec<String> = (0..500).map(|i| format!("user_{:04}", i)).filter(|key| !root_keys.contains(key)).collect();
+        assert_eq!(keys, expected);
+        assert!(tx.get_collection(LOST_AND_FOUND.to_string()).unwrap().is_none());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn lost_meta_moves_everything_to_lost_and_found() {
+        create_source("./db_salvage_test_internal_5");
+        overwrite_page("./db_salvage_test_internal_5", 0, 0);
+
+        let same_file = Options {
+            path: "./db_salvage_test_internal_5",
+            ..DEFAULT_OPTIONS
+        };
+        assert!(DB::salvage("./db_salvage_test_internal_5", same_file).is_err());
+        let (db, report) = DB::salvage("./db_salvage_test_internal_5", new_options("./db_salvage_test_internal_6")).unwrap();
+        assert!(report.meta_lost);
+        assert!(report.orphans_unplaced > 0);
+        assert!(db.check().unwrap().is_ok());
+
+        let tx = db.read_tx().unwrap();
+        let lost_and_found = tx.get_collection(LOST_AND_FOUND.to_string()).unwrap().unwrap();
+        let mut keys = vec![];
+        for info in lost_and_found.list_sub_collections(&tx).unwrap() {
+            let page = lost_and_found.get_sub_collection(info.name, &tx).unwrap().unwrap();
+            keys.extend(page.iter(&tx).map(|item| item.unwrap().key).filter(|key| key.starts_with("user_")));
+        }
+        keys.sort();
+        assert_eq!(keys, (0..500).map(|i| format!("user_{:04}", i)).collect::<Vec<String>>());
+        tx.commit().unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Helpers shared by the tests of several modules.
+
+use std::{fs, path::Path};
+
+use crate::{dal::{Options, DEFAULT_OPTIONS}, db::DB, tx::ReadTx};
+
+/// Removes the file at `path`, if an earlier run left one behind.
+pub(crate) fn remove(path: &str) {
+    if Path::new(path).exists() {
+        match fs::remove_file(Path::new(path)) {
+            Ok(()) => {},
+            Err(_) => {
+                assert!(false, "Failed to clean up db file");
+            }
+        }
+    }
+}
+
+/// Options for a new database at `path`, with the file of an earlier run removed.
+pub(crate) fn new_options(path: &'static str) -> Options {
+    remove(path);
+    Options {
+        page_size: DEFAULT_OPTIONS.page_size,
+        min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
+        max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
+        path,
+        ..DEFAULT_OPTIONS
+    }
+}
+
+/// Opens a new database at `path`.
+pub(crate) fn open(path: &'static str) -> DB {
+    DB::open(new_options(path)).unwrap()
+}
+
+/// Opens the database already at `path`, such as a copy or a restored file.
+pub(crate) fn open_existing(path: &'static str) -> DB {
+    DB::open(Options {
+        path,
+        ..DEFAULT_OPTIONS
+    }).unwrap()
+}
+
+/// Fills `users` with 3000 keys and `tenants/acme` with 100, then deletes most of `users`, leaving free pages
+/// behind.
+pub(crate) fn fill(db: &DB) {
+    db.update(|tx| {
+        let mut users = tx.create_collection("users".to_string())?;
+        for i in 0..3000 {
+            users.put(format!("user_{:05}", i), format!("value_{}", i).as_bytes().to_owned(), tx)?;
+        }
+        let mut tenants = tx.create_collection("tenants".to_string())?;
+        let mut acme = tenants.create_sub_collection("acme".to_string(), tx)?;
+        for i in 0..100 {
+            acme.put(format!("key_{:04}", i), vec![7; 30], tx)?;
+        }
+        acme.set_sequence(11, tx)?;
+        tx.create_collection("empty".to_string())?;
+        Ok(())
+    }).unwrap();
+    db.update(|tx| {
+        let mut users = tx.get_collection("users".to_string())?.unwrap();
+        for i in (0..3000).filter(|i| i % 10 != 0) {
+            users.remove(format!("user_{:05}", i), tx)?;
+        }
+        Ok(())
+    }).unwrap();
+}
+
+/// Asserts that `db` holds what `fill` left.
+pub(crate) fn assert_contents(db: &DB) {
+    let tx = db.read_tx().unwrap();
+    let users = tx.get_collection("users".to_string()).unwrap().unwrap();
+    let keys: Vec<String> = users.iter(&tx).map(|item| item.unwrap().key).collect();
+    assert_eq!(keys, (0..3000).filter(|i| i % 10 == 0).map(|i| format!("user_{:05}", i)).collect::<Vec<String>>());
+    assert_eq!(users.find("user_00100".to_string(), &tx).unwrap().unwrap().value, "value_100".as_bytes().to_owned());
+
+    let acme = tx.get_collection("tenants".to_string()).unwrap().unwrap().get_sub_collection("acme".to_string(), &tx).unwrap().unwrap();
+    assert_eq!(acme.iter(&tx).count(), 100);
+    assert_eq!(acme.sequence(), 11);
+    assert_eq!(tx.get_collection("empty".to_string()).unwrap().unwrap().iter(&tx).count(), 0);
+    tx.commit().unwrap();
+}
@@ -1,79 +1,120 @@
-use std::{collections::HashMap, sync::{RwLockReadGuard, RwLockWriteGuard}};
-use crate::{collection::Collection, db::DB, dal::DAL, error::CustomError, node::{Item, Node}};
+use std::{collections::HashMap, io::Write, path::Path, sync::{atomic::{AtomicU64, Ordering}, PoisonError, RwLockReadGuard}, time::Instant};
+use crate::{backup, bucket::{Bucket, ReadBucket}, collection::{Collection, CollectionInfo, Location}, dal::DAL, db::DbRef, error::CustomError, lock::TxLease, metrics::Metrics, node::{Item, Node}};
+
+/// A read-only transaction that owns its handle on the database, so it can be stored, sent to another thread
+/// or held across an `.await`. Created by `DB::read_tx_owned`.
+pub type OwnedTx = Tx<'static>;
+
+/// A read-write transaction that owns its handle on the database. Created by `DB::write_tx_owned`.
+pub type OwnedTxMut = TxMut<'static>;
+
+/// Read access shared by `Tx` and `TxMut`. Lookups are written once against this trait and work
+/// inside either kind of transaction, seeing the pending changes of a `TxMut`.
+pub trait ReadTx {
+    fn dal(&self) -> RwLockReadGuard<'_, DAL>;
+
+    fn get_node(&self, page_id: u64) -> Result<Node, CustomError>;
+
+    fn get_root_collection(&self) -> Result<Collection, CustomError>;
+
+    fn get_collection(&self, name: String) -> Result<Option<Collection>, CustomError>
+    where
+        Self: Sized,
+    {
+        match self.get_root_collection() {
+            Ok(root_collection) => root_collection.get_sub_collection(name, self),
+            Err(error) => Err(error)
+        }
+    }
+
+    /// Lists the collections in name order, together with their root page, key count and sequence.
+    fn list_collections(&self) -> Result<Vec<CollectionInfo>, CustomError>
+    where
+        Self: Sized,
+    {
+        match self.get_root_collection() {
+            Ok(root_collection) => root_collection.list_sub_collections(self),
+            Err(error) => Err(error)
+        }
+    }
+}
 
 pub struct Tx<'a> {
-    pub dal: RwLockReadGuard<'a, DAL>,
+    db: DbRef<'a>,
+    _lease: TxLease,
 }
 
 impl <'a> Tx<'a> {
-    pub fn new(db: &'a DB) -> Tx<'a> {
-        let dal = db.dal.read().unwrap();
+    pub(crate) fn new(db: DbRef<'a>, lease: TxLease) -> Tx<'a> {
         Tx {
-            dal,
+            db,
+            _lease: lease,
         }
     }
 
-    pub fn new_node(&mut self, items: Vec<Item>, child_nodes: Vec<u64>) -> Result<Node, CustomError> {
-        Err(CustomError::new("Readable Transaction cannot create new node".to_string()))
-    }
-
-    pub fn get_node(&self, page_id: u64) -> Result<Node, CustomError> {
-        self.dal.get_node(page_id)
+    /// Opens the collection `name` as a handle bound to this transaction.
+    pub fn collection(&self, name: String) -> Result<Option<ReadBucket<'_, 'a>>, CustomError> {
+        match self.get_collection(name) {
+            Ok(Some(collection)) => Ok(Some(ReadBucket::new(collection, self))),
+            Ok(None) => Ok(None),
+            Err(error) => Err(error)
+        }
     }
 
-    pub fn write_node(&mut self, node: &mut Node) -> Result<(), CustomError> {
-        Err(CustomError::new("Readable Transaction cannot write node".to_string()))
+    /// Writes the database as this transaction sees it to `writer`, in a form that opens as a database file, and
+    /// returns the number of bytes written. Only the pages reachable from the collections are copied, so the copy
+    /// holds no free pages. Other readers keep going, writers wait until the transaction ends.
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<u64, CustomError> {
+        backup::write_to(self, writer)
     }
 
-    pub fn delete_node(&mut self, node: &Node) -> Result<(), CustomError> {
-        Err(CustomError::new("Readable Transaction cannot write node".to_string()))
+    /// Writes the copy of `write_to` into a new file at `path`, which must not exist yet.
+    pub fn copy_file<P: AsRef<Path>>(&self, path: P) -> Result<u64, CustomError> {
+        backup::copy_file(self, path.as_ref())
     }
 
     pub fn rollback(self) -> Result<(), CustomError> {
-        drop(self.dal);
+        drop(self);
 
         Ok(())
     }
 
     pub fn commit(self) -> Result<(), CustomError> {
-        drop(self.dal);
+        drop(self);
         
         Ok(())
     }
+}
 
-    pub fn get_root_collection(&self) -> Result<Collection, CustomError> {
-        match self.dal.meta {
-            Some(ref meta) => Ok(Collection::new("".to_string(), meta.root)),
-            None => {
-                return Err(CustomError::new("Meta not initialized".to_string()));
-            }
-        } 
+impl ReadTx for Tx<'_> {
+    fn dal(&self) -> RwLockReadGuard<'_, DAL> {
+        self.db.read_dal()
     }
 
-    pub fn get_collection(&self, name: String) -> Result<Option<Collection>, CustomError> {
-        match self.get_root_collection() {
-            Ok(root_collection) => {
-                match root_collection.find(name, self) {
-                    Ok(Some(item)) => Ok(Some(Collection::deserialize(item))),
-                    Ok(None) => Ok(None),
-                    Err(error) => {
-                        Err(error)
-                    }
-                }
-            }
-            Err(error) => Err(error)
-        }
-    }
-
-    pub fn create_collection(&mut self, name: String) -> Result<Collection, CustomError> {
-        Err(CustomError::new("Readable Transaction cannot create collection".to_string()))
+    fn get_node(&self, page_id: u64) -> Result<Node, CustomError> {
+        self.dal().get_node(page_id)
     }
 
-    pub fn delete_collection(&mut self, name: String) -> Result<(), CustomError> {
-        Err(CustomError::new("Readable Transaction cannot create collection".to_string()))
+    fn get_root_collection(&self) -> Result<Collection, CustomError> {
+        match self.dal().meta {
+            Some(ref meta) => Ok(Collection::root_directory(meta.root)),
+            None => {
+                Err(CustomError::new("Meta not initialized".to_string()))
+            }
+        } 
     }
+}
 
+static NEXT_SAVEPOINT_ID: AtomicU64 = AtomicU64::new(0);
 
+/// State of a `TxMut` captured by `TxMut::savepoint`, which `TxMut::rollback_to` restores.
+#[derive(Debug)]
+pub struct Savepoint {
+    id: u64,
+    meta_root: Option<u64>,
+    dirty_nodes: HashMap<u64, Node>,
+    pages_to_delete: usize,
+    allocated_page_ids: usize,
 }
 
 pub struct TxMut<'a> {
@@ -82,26 +123,36 @@ pub struct TxMut<'a> {
     pages_to_delete: Vec<u64>,
 
     allocated_page_ids: Vec<u64>,
+    savepoints: Vec<u64>,
+    /// Set once committed or rolled back, a transaction dropped before that counts as rolled back.
+    finished: bool,
 
-    pub dal: RwLockWriteGuard<'a, DAL>,
+    db: DbRef<'a>,
+    _lease: TxLease,
 }
 
 impl<'a> TxMut<'a> {
-    pub fn new(db: &'a DB) -> TxMut<'a> {
-        let dal = db.dal.write().unwrap();
+    pub(crate) fn new(db: DbRef<'a>, lease: TxLease) -> TxMut<'a> {
         TxMut {
             meta_root: None,
             dirty_nodes: HashMap::new(),
             pages_to_delete: vec![],
             allocated_page_ids: vec![],
+            savepoints: vec![],
+            finished: false,
 
-            dal
+            db,
+            _lease: lease,
         }  
     }
 
     pub fn new_node(&mut self, items: Vec<Item>, child_nodes: Vec<u64>) -> Result<Node, CustomError> {
-        match self.dal.get_next_page() {
-            Ok(page_id) => Ok(Node::new(page_id, items, child_nodes)),
+        let next_page = self.db.write_dal().get_next_page();
+        match next_page {
+            Ok(page_id) => {
+                self.allocated_page_ids.push(page_id);
+                Ok(Node::new(page_id, items, child_nodes))
+            }
             Err(error) => {
                 panic!("Error creating new node: {:?}", error);
             }
@@ -109,28 +160,63 @@ impl<'a> TxMut<'a> {
         
     }
 
-    pub fn get_node(&self, page_id: u64) -> Result<Node, CustomError> {
-        match self.dirty_nodes.get(&page_id) {
-            Some(node) => Ok((*node).clone()),
-            None => self.dal.get_node(page_id)
-        }
-    }
-
     pub fn write_node(&mut self, node: &mut Node) -> Result<(), CustomError> {
         self.dirty_nodes.insert(node.page_id, (*node).clone());
         Ok(())
     }
 
     pub fn delete_node(&mut self, node: &Node) {
-        self.pages_to_delete.push(node.page_id);
+        self.delete_page(node.page_id);
     }
 
-    pub fn rollback(mut self) -> Result<(), CustomError> {
-        self.meta_root.take();
-        self.dirty_nodes.drain();
-        self.pages_to_delete.drain(0..);
-        while let Some(page_id) = self.allocated_page_ids.pop() {
-            match self.dal.freelist {
+    pub(crate) fn delete_page(&mut self, page_id: u64) {
+        // Pending changes to a freed page are dropped so the commit does not write it.
+        self.dirty_nodes.remove(&page_id);
+        self.pages_to_delete.push(page_id);
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.db.metrics
+    }
+
+    /// Captures the pending changes of the transaction so they can later be undone with `rollback_to`.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = NEXT_SAVEPOINT_ID.fetch_add(1, Ordering::Relaxed);
+        self.savepoints.push(id);
+
+        Savepoint {
+            id,
+            meta_root: self.meta_root,
+            dirty_nodes: self.dirty_nodes.clone(),
+            pages_to_delete: self.pages_to_delete.len(),
+            allocated_page_ids: self.allocated_page_ids.len(),
+        }
+    }
+
+    /// Undoes every change made since `savepoint` was taken, releasing the pages allocated since then.
+    /// The savepoint stays usable, while savepoints taken after it are invalidated. `Collection`s
+    /// obtained after the savepoint may be stale and should be fetched again.
+    pub fn rollback_to(&mut self, savepoint: &Savepoint) -> Result<(), CustomError> {
+        match self.savepoints.iter().position(|id| *id == savepoint.id) {
+            Some(position) => {
+                self.savepoints.truncate(position + 1);
+            }
+            None => {
+                return Err(CustomError::new("Savepoint does not belong to this transaction or was invalidated".to_string()));
+            }
+        }
+
+        self.meta_root = savepoint.meta_root;
+        self.dirty_nodes = savepoint.dirty_nodes.clone();
+        self.pages_to_delete.truncate(savepoint.pages_to_delete);
+        self.release_allocated_pages(savepoint.allocated_page_ids)
+    }
+
+    fn release_allocated_pages(&mut self, keep: usize) -> Result<(), CustomError> {
+        let mut dal = self.db.write_dal();
+        while self.allocated_page_ids.len() > keep {
+            let page_id = self.allocated_page_ids.pop().unwrap();
+            match dal.freelist {
                 Some(ref mut freelist) => {
                     freelist.release_page(page_id)
                 }
@@ -140,14 +226,33 @@ impl<'a> TxMut<'a> {
             }
         }
 
-        drop(self.dal);
-
         Ok(())
     }
 
+    pub fn rollback(mut self) -> Result<(), CustomError> {
+        self.finished = true;
+        self.db.metrics.rollback();
+        self.meta_root.take();
+        self.dirty_nodes.drain();
+        self.pages_to_delete.drain(0..);
+        self.release_allocated_pages(0)
+    }
+
+    /// Writes the changes and gives the transaction the next txid. Errors shrinking the file or archiving the
+    /// commit come after the changes are committed, so they are reported through `Observer::after_commit_error`
+    /// instead of being returned.
     pub fn commit(mut self) -> Result<(), CustomError> {
+        let started = Instant::now();
+        let mut dal = self.db.write_dal();
+        let txid = match dal.meta {
+            Some(ref meta) => meta.txid + 1,
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()));
+            }
+        };
+
         for node in self.dirty_nodes.values_mut() {
-            match self.dal.write_node(node) {
+            match dal.write_node(node) {
                 Ok(()) => {}
                 Err(error) => {
                     return Err(error);
@@ -156,7 +261,7 @@ impl<'a> TxMut<'a> {
         }
 
         while let Some(page_id) = self.pages_to_delete.pop() {
-            match self.dal.freelist {
+            match dal.freelist {
                 Some(ref mut freelist) => {
                     freelist.release_page(page_id)
                 }
@@ -166,61 +271,75 @@ impl<'a> TxMut<'a> {
             }
         }
 
-        match self.dal.write_freelist() {
+        // Free pages at the end of the file are given back to the file system once the meta page is written.
+        match dal.freelist {
+            Some(ref mut freelist) => {
+                freelist.trim();
+            }
+            None => {
+                return Err(CustomError::new("Freelist not initialized".to_string()));
+            }
+        }
+
+        match dal.write_freelist() {
             Ok(_) => {}
             Err(error) => {
                 return Err(error);
             }
         }
 
-        if let Some(meta_root) = self.meta_root {
-            match self.dal.meta {
-                Some(ref mut meta) => {
-                    meta.root = meta_root;
-                    match self.dal.write_meta() {
-                        Ok(_) => {}
-                        Err(error) => {
-                            return Err(error);
-                        }
-                    }
+        if let Some(ref page_txids) = dal.page_txids {
+            match page_txids.stamp(self.dirty_nodes.keys().copied(), txid) {
+                Ok(()) => {},
+                Err(error) => {
+                    return Err(error);
                 }
-                None => {
-                    return Err(CustomError::new("Meta not initialized".to_string()))
+            }
+        }
+
+        match dal.meta {
+            Some(ref mut meta) => {
+                if let Some(meta_root) = self.meta_root {
+                    meta.root = meta_root;
                 }
+                meta.txid = txid;
+            }
+            None => {
+                return Err(CustomError::new("Meta not initialized".to_string()))
+            }
+        }
+        match dal.write_meta() {
+            Ok(_) => {}
+            Err(error) => {
+                return Err(error);
             }
         }
 
+        // Committed once the meta page is written, so the allocated pages stay in use whatever fails below.
+        let page_ids: Vec<u64> = self.dirty_nodes.drain().map(|(page_id, _)| page_id).collect();
         self.meta_root.take();
-        self.dirty_nodes.drain();
         self.allocated_page_ids.drain(0..);
+        self.finished = true;
+        self.db.metrics.commit(started.elapsed());
 
-        drop(self.dal);
-        
-        Ok(())
-    }
-
-    pub fn get_root_collection(&mut self) -> Result<Collection, CustomError> {
-        if let Some(meta_root) = self.meta_root {
-            return Ok(Collection::new("".to_string(), meta_root));
+        // Failing to shrink the file or archive the commit does not undo it, so it is only reported.
+        if let Err(error) = dal.truncate() {
+            self.db.metrics.after_commit_error(&error);
         }
-
-        match self.dal.meta {
-            Some(ref meta) => Ok(Collection::new("".to_string(), meta.root)),
-            None => {
-                return Err(CustomError::new("Meta not initialized".to_string()));
+        if let Some(ref archive) = self.db.archive {
+            if let Err(error) = archive.lock().unwrap_or_else(PoisonError::into_inner).record(&dal, &page_ids) {
+                self.db.metrics.after_commit_error(&error);
             }
-        } 
+        }
+
+        Ok(())
     }
 
-    pub fn get_collection(&mut self, name: String) -> Result<Option<Collection>, CustomError> {
-        match self.get_root_collection() {
-            Ok(root_collection) => {
-                match root_collection.find_mut(name, self) {
-                    Ok(Some(item)) => Ok(Some(Collection::deserialize(item))),
-                    Ok(None) => Ok(None),
-                    Err(error) => Err(error)
-                }
-            }
+    /// Opens the collection `name` as a handle bound to this transaction, see `Bucket`.
+    pub fn collection(&mut self, name: String) -> Result<Option<Bucket<'_, 'a>>, CustomError> {
+        match self.get_collection(name) {
+            Ok(Some(collection)) => Ok(Some(Bucket::new(collection, self))),
+            Ok(None) => Ok(None),
             Err(error) => Err(error)
         }
     }
@@ -268,34 +387,167 @@ impl<'a> TxMut<'a> {
         }
     }
 
+    /// Deletes the collection `name` and its sub-collections, releasing every page of their trees once the
+    /// transaction commits.
     pub fn delete_collection(&mut self, name: String) -> Result<(), CustomError> {
+        match self.get_root_collection() {
+            Ok(mut root_collection) => root_collection.delete_sub_collection(name, self),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn remove_collection_entry(&mut self, name: String) -> Result<(), CustomError> {
         match self.get_root_collection() {
             Ok(mut root_collection) => root_collection.remove(name, self),
             Err(error) => Err(error)
         }
     }
 
+    /// Renames the collection `old` to `new`. Only its entry in the root collection moves, the data is not copied.
+    pub fn rename_collection(&mut self, old: String, new: String) -> Result<(), CustomError> {
+        let mut collection = match self.get_collection(old.clone()) {
+            Ok(Some(collection)) => collection,
+            Ok(None) => {
+                return Err(CustomError::new(format!("Collection not found: {}", old)));
+            }
+            Err(error) => {
+                return Err(error);
+            }
+        };
+        if old == new {
+            return Ok(());
+        }
+
+        match self.get_collection(new.clone()) {
+            Ok(Some(_)) => {
+                return Err(CustomError::new(format!("Collection already exists: {}", new)));
+            }
+            Ok(None) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        match self.remove_collection_entry(old) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+        collection.name = new;
+        self.update_collection(&mut collection)
+    }
+
+    /// Writes the entry of `collection`, for example after its root moved. A moved root of the directory of
+    /// top-level collections goes to the meta page, one of a directory of sub-collections to the owning collection.
+    /// The sub-collection root and sequence already stored win over those of `collection`, which may be stale
+    /// when they were changed through another handle.
     pub fn update_collection(&mut self, collection: &mut Collection) -> Result<(), CustomError> {
-        let collection_in_bytes_item = collection.serialize();
-        
-        match self.get_root_collection() {
-            Ok(ref mut root_collection) => {
-                match root_collection.put(collection.name.clone(), collection_in_bytes_item.value, self) {
-                    Ok(()) => Ok(()),
+        self.write_collection(collection, true)
+    }
+
+    /// Like `update_collection`, but with `keep_stored` false every field of `collection` is written as is.
+    pub(crate) fn write_collection(&mut self, collection: &mut Collection, keep_stored: bool) -> Result<(), CustomError> {
+        match collection.location().clone() {
+            Location::Root => {
+                self.meta_root = Some(collection.root);
+                Ok(())
+            }
+            Location::Directory(owner_path) => {
+                match Collection::at_path(&owner_path, self) {
+                    Ok(Some(mut owner)) => {
+                        owner.set_children(collection.root);
+                        self.write_collection(&mut owner, false)
+                    }
+                    Ok(None) => Err(CustomError::new(format!("Collection not found: {}", owner_path.join("/")))),
                     Err(error) => Err(error)
                 }
             }
+            Location::Entry(parent_path) => self.write_collection_entry(collection, &parent_path, keep_stored)
+        }
+    }
+
+    /// Puts the entry of `collection` into the directory of the collection at `parent_path`.
+    fn write_collection_entry(&mut self, collection: &mut Collection, parent_path: &[String], keep_stored: bool) -> Result<(), CustomError> {
+        let mut directory = match Collection::at_path(parent_path, self) {
+            Ok(Some(parent)) => parent.sub_directory(),
+            Ok(None) => {
+                return Err(CustomError::new(format!("Collection not found: {}", parent_path.join("/"))));
+            }
             Err(error) => {
-                Err(error)
+                return Err(error);
+            }
+        };
+
+        if keep_stored {
+            match directory.find(collection.name.clone(), self) {
+                Ok(Some(item)) => collection.keep_stored(&Collection::deserialize(item)),
+                Ok(None) => {}
+                Err(error) => {
+                    return Err(error);
+                }
+            }
+        }
+
+        let directory_root = directory.root;
+        let collection_in_bytes_item = collection.serialize();
+        match directory.put(collection.name.clone(), collection_in_bytes_item.value, self) {
+            Ok(()) => {}
+            Err(error) => {
+                return Err(error);
             }
         }
+
+        // The directory got its first node or a new root.
+        if directory.root != directory_root {
+            return self.update_collection(&mut directory);
+        }
+        Ok(())
+    }
+
+}
+
+impl ReadTx for TxMut<'_> {
+    fn dal(&self) -> RwLockReadGuard<'_, DAL> {
+        self.db.read_dal()
+    }
+
+    fn get_node(&self, page_id: u64) -> Result<Node, CustomError> {
+        match self.dirty_nodes.get(&page_id) {
+            Some(node) => Ok((*node).clone()),
+            None => self.dal().get_node(page_id)
+        }
     }
 
+    fn get_root_collection(&self) -> Result<Collection, CustomError> {
+        if let Some(meta_root) = self.meta_root {
+            return Ok(Collection::root_directory(meta_root));
+        }
+
+        match self.dal().meta {
+            Some(ref meta) => Ok(Collection::root_directory(meta.root)),
+            None => {
+                Err(CustomError::new("Meta not initialized".to_string()))
+            }
+        } 
+    }
+}
+
+impl Drop for TxMut<'_> {
+    /// A transaction that was neither committed nor rolled back, for example because its thread panicked,
+    /// gives the pages it allocated back to the freelist.
+    fn drop(&mut self) {
+        if !self.finished {
+            self.db.metrics.rollback();
+        }
+        let _ = self.release_allocated_pages(0);
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
-    use crate::{collection::Collection, dal::{Options, DEFAULT_OPTIONS}, db::DB, node::Item};
+    use crate::{collection::{Collection, CollectionInfo}, consts::{PAGE_ID_SIZE, PAGE_SIZE}, dal::{Options, DEFAULT_OPTIONS}, db::DB, error::CustomError, freelist::Freelist, node::Item, test_util::{new_options, open}, tx::{ReadTx, TxMut}};
     use std::{fs, path::Path, sync::Arc, thread};
 
     #[test]
@@ -304,7 +556,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_tx_test_internal_1"
+            path: "./db_tx_test_internal_1",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -318,7 +571,7 @@ mod tests {
 
         match DB::open(options) {
             Ok(db) => {
-                let mut tx = db.write_tx();
+                let mut tx = db.write_tx().unwrap();
 
                 match tx.create_collection("test_collection".to_string()) {
                     Ok(collection) => {
@@ -336,11 +589,11 @@ mod tests {
                     }
                 }
 
-                let tx = db.read_tx();
+                let tx = db.read_tx().unwrap();
 
                 match tx.get_collection("test_collection".to_string()) {
                     Ok(collection) => {
-                        assert_eq!(collection.is_some(), true);
+                        assert!(collection.is_some());
                         assert_eq!(collection.unwrap().name, "test_collection".to_string());
                     }
                     Err(error) => {
@@ -368,7 +621,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_tx_test_internal_2"
+            path: "./db_tx_test_internal_2",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -382,8 +636,8 @@ mod tests {
 
         match DB::open(options) {
             Ok(db) => {
-                let tx1 = db.read_tx();
-                let tx2 = db.read_tx();
+                let tx1 = db.read_tx().unwrap();
+                let tx2 = db.read_tx().unwrap();
 
                 match tx1.get_collection("non_existing_collection".to_string()) {
                     Ok(_) => {}
@@ -399,8 +653,8 @@ mod tests {
                     }
                 }
 
-                tx1.commit();
-                tx2.commit();
+                tx1.commit().unwrap();
+                tx2.commit().unwrap();
             }
             Err(_) => {
                 assert!(false, "DB not created successfully!")
@@ -414,7 +668,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_tx_test_internal_3"
+            path: "./db_tx_test_internal_3",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -430,16 +685,16 @@ mod tests {
             Ok(db) => {
                 let db = Arc::new(db);
 
-                let tx = db.read_tx();
+                let tx = db.read_tx().unwrap();
 
                 let db_clone = Arc::clone(&db);
                 let t1 = thread::spawn(move || {
-                    let mut tx = db_clone.write_tx();
+                    let mut tx = db_clone.write_tx().unwrap();
                     
                 
                     let db_new_clone = Arc::clone(&db_clone);
                     let t2 = thread::spawn(move|| {
-                        let tx = db_new_clone.read_tx();
+                        let tx = db_new_clone.read_tx().unwrap();
 
                         match tx.get_collection("test_collection".to_string()) {
                             Ok(Some(collection)) => {
@@ -468,7 +723,7 @@ mod tests {
                         }
                     }
 
-                    tx.commit();
+                    tx.commit().unwrap();
                     
                     t2.join().unwrap();
                 });
@@ -483,7 +738,7 @@ mod tests {
                     }
                 }
 
-                tx.commit();
+                tx.commit().unwrap();
 
                 t1.join().unwrap();
             }
@@ -499,7 +754,8 @@ mod tests {
             page_size: DEFAULT_OPTIONS.page_size,
             min_fill_percent: DEFAULT_OPTIONS.min_fill_percent,
             max_fill_percent: DEFAULT_OPTIONS.max_fill_percent,
-            path: "./db_tx_test_internal_4"
+            path: "./db_tx_test_internal_4",
+            ..DEFAULT_OPTIONS
         };
 
         if Path::new(&options.path).exists() {
@@ -513,7 +769,7 @@ mod tests {
 
         match DB::open(options) {
             Ok(db) => {
-                let mut tx = db.write_tx();
+                let mut tx = db.write_tx().unwrap();
 
                 let mut child_0;
                 match tx.new_node(vec![Item::new("1".to_string(), "1".as_bytes().to_owned()), Item::new("2".to_string(), "2".as_bytes().to_owned())], vec![]) {
@@ -577,7 +833,7 @@ mod tests {
                     }
                 }
 
-                let mut tx2 = db.write_tx();
+                let mut tx2 = db.write_tx().unwrap();
 
                 match tx2.get_collection("test_collection".to_string()) {
                     Ok(Some(mut collection)) => {
@@ -604,11 +860,11 @@ mod tests {
                     }
                 }
 
-                let mut tx3 = db.read_tx();
+                let tx3 = db.read_tx().unwrap();
 
                 match tx3.get_collection("test_collection".to_string()) {
                     Ok(Some(collection)) => {
-                        match collection.find("9".to_string(), &mut tx3) {
+                        match collection.find("9".to_string(), &tx3) {
                             Ok(Some(item)) => {
                                 assert!(false, "Item {:?} was found, rollback failed", item);
                             }
@@ -626,11 +882,342 @@ mod tests {
                     }
                 }
 
-                tx3.commit();
+                tx3.commit().unwrap();
             }
             Err(_) => {
                 assert!(false, "DB not created successfully!")
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rollback_to_savepoint() {
+        let db = open("./db_tx_test_internal_5");
+        let mut tx = db.write_tx().unwrap();
+
+        let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+        collection.put("1".to_string(), "1".as_bytes().to_owned(), &mut tx).unwrap();
+
+        let savepoint = tx.savepoint();
+        let released_pages = tx.dal().freelist.as_ref().unwrap().released_pages.len();
+
+        collection.put("2".to_string(), "2".as_bytes().to_owned(), &mut tx).unwrap();
+        tx.create_collection("other_collection".to_string()).unwrap();
+        let later_savepoint = tx.savepoint();
+
+        match tx.rollback_to(&savepoint) {
+            Ok(()) => {}
+            Err(error) => {
+                assert!(false, "Failed to rollback to savepoint with error: {:?}", error);
+            }
+        }
+        assert_eq!(tx.dal().freelist.as_ref().unwrap().released_pages.len(), released_pages + 1);
+        assert!(tx.rollback_to(&later_savepoint).is_err(), "Invalidated savepoint was accepted");
+
+        let collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert!(collection.find("1".to_string(), &tx).unwrap().is_some());
+        assert!(collection.find("2".to_string(), &tx).unwrap().is_none());
+        assert!(tx.get_collection("other_collection".to_string()).unwrap().is_none());
+
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        let collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        assert!(collection.find("1".to_string(), &tx).unwrap().is_some());
+        assert!(collection.find("2".to_string(), &tx).unwrap().is_none());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn read_helpers_work_in_both_transactions() {
+        fn value_of<T: ReadTx>(tx: &T, key: &str) -> Option<Vec<u8>> {
+            let collection = tx.get_collection("test_collection".to_string()).unwrap()?;
+            collection.find(key.to_string(), tx).unwrap().map(|item| item.value)
+        }
+
+        let db = open("./db_tx_test_internal_6");
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+        collection.put("1".to_string(), "1".as_bytes().to_owned(), &mut tx).unwrap();
+
+        // The write transaction sees its own pending changes.
+        assert_eq!(value_of(&tx, "1"), Some("1".as_bytes().to_owned()));
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        assert_eq!(value_of(&tx, "1"), Some("1".as_bytes().to_owned()));
+        assert_eq!(value_of(&tx, "2"), None);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn many_collections() {
+        let db = open("./db_tx_test_internal_7");
+
+        // Enough collections to split the root collection, whose new root must end up in the meta page.
+        let mut tx = db.write_tx().unwrap();
+        for i in 0..300 {
+            let mut collection = tx.create_collection(format!("collection_{:03}", i)).unwrap();
+            collection.put("key".to_string(), format!("value_{}", i).as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        for i in 0..300 {
+            let collection = tx.get_collection(format!("collection_{:03}", i)).unwrap().unwrap();
+            let item = collection.find("key".to_string(), &tx).unwrap().unwrap();
+            assert_eq!(item.value, format!("value_{}", i).as_bytes().to_owned());
+        }
+        let root_collection = tx.get_root_collection().unwrap();
+        assert!(root_collection.find("".to_string(), &tx).unwrap().is_none());
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn list_and_rename_collections() {
+        let db = open("./db_tx_test_internal_8");
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection_a = tx.create_collection("collection_a".to_string()).unwrap();
+        for i in 0..3 {
+            collection_a.put(i.to_string(), "value".as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        collection_a.next_sequence(&mut tx).unwrap();
+        collection_a.next_sequence(&mut tx).unwrap();
+        let collection_b = tx.create_collection("collection_b".to_string()).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        let collections = tx.list_collections().unwrap();
+        assert_eq!(collections.len(), 2);
+        assert_eq!(collections[0], CollectionInfo {
+            name: "collection_a".to_string(),
+            root: collection_a.root,
+            key_count: 3,
+            sequence: 2,
+        });
+        assert_eq!(collections[1].name, "collection_b".to_string());
+        assert_eq!(collections[1].root, collection_b.root);
+        assert_eq!(collections[1].key_count, 0);
+        tx.commit().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        assert!(tx.rename_collection("collection_a".to_string(), "collection_b".to_string()).is_err());
+        assert!(tx.rename_collection("missing".to_string(), "collection_c".to_string()).is_err());
+        tx.rename_collection("collection_a".to_string(), "collection_c".to_string()).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        assert!(tx.get_collection("collection_a".to_string()).unwrap().is_none());
+        let collection_c = tx.get_collection("collection_c".to_string()).unwrap().unwrap();
+        assert_eq!(collection_c.root, collection_a.root);
+        assert!(collection_c.find("2".to_string(), &tx).unwrap().is_some());
+        let names: Vec<String> = tx.list_collections().unwrap().into_iter().map(|info| info.name).collect();
+        assert_eq!(names, vec!["collection_b".to_string(), "collection_c".to_string()]);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn delete_collection_frees_pages() {
+        let db = open("./db_tx_test_internal_9");
+
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.create_collection("test_collection".to_string()).unwrap();
+        for i in 0..500 {
+            collection.put(format!("key_{:03}", i), format!("value_{}", i).as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        let collection = tx.get_collection("test_collection".to_string()).unwrap().unwrap();
+        let pages = collection.pages(&tx).unwrap();
+        assert!(pages.len() > 1);
+        let max_page = tx.dal().freelist.as_ref().unwrap().max_page;
+        let released_pages = tx.dal().freelist.as_ref().unwrap().released_pages.len();
+        tx.commit().unwrap();
+
+        let mut tx = db.write_tx().unwrap();
+        tx.delete_collection("test_collection".to_string()).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.read_tx().unwrap();
+        assert!(tx.get_collection("test_collection".to_string()).unwrap().is_none());
+        let dal = tx.dal();
+        let freelist = dal.freelist.as_ref().unwrap();
+        // Freed pages at the end of the file are cut off instead of being listed.
+        let trimmed = (max_page - freelist.max_page) as usize;
+        assert_eq!(freelist.released_pages.len() + trimmed, released_pages + pages.len());
+        for page_id in &pages {
+            assert!(freelist.released_pages.contains(page_id) || *page_id > freelist.max_page);
+        }
+        drop(dal);
+        tx.commit().unwrap();
+
+        // The freed pages are reused instead of growing the file.
+        let mut tx = db.write_tx().unwrap();
+        let mut collection = tx.create_collection("other_collection".to_string()).unwrap();
+        for i in 0..500 {
+            collection.put(format!("key_{:03}", i), format!("value_{}", i).as_bytes().to_owned(), &mut tx).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(db.read_tx().unwrap().dal().freelist.as_ref().unwrap().max_page, max_page);
+    }
+
+    #[test]
+    fn commit_cuts_off_free_pages_at_the_end() {
+        let options = new_options("./db_tx_test_internal_10");
+
+        let db = DB::open(options.clone()).unwrap();
+        db.update(|tx| {
+            let mut collection = tx.create_collection("small".to_string())?;
+            collection.put("key".to_string(), "value".as_bytes().to_owned(), tx)?;
+            Ok(())
+        }).unwrap();
+        let size = fs::metadata(options.path).unwrap().len();
+
+        db.update(|tx| {
+            let mut collection = tx.create_collection("large".to_string())?;
+            for i in 0..2000 {
+                collection.put(format!("key_{:04}", i), vec![0u8; 100], tx)?;
+            }
+            Ok(())
+        }).unwrap();
+        let grown = fs::metadata(options.path).unwrap().len();
+        assert!(grown > size);
+
+        db.update(|tx| tx.delete_collection("large".to_string())).unwrap();
+        let tx = db.read_tx().unwrap();
+        let max_page = tx.dal().freelist.as_ref().unwrap().max_page;
+        assert!(tx.dal().freelist.as_ref().unwrap().released_pages.iter().all(|page_id| *page_id < max_page));
+        tx.commit().unwrap();
+        let shrunk = fs::metadata(options.path).unwrap().len();
+        assert!(shrunk < grown);
+        assert_eq!(shrunk, (max_page + 1) * options.page_size as u64);
+        assert!(db.check().unwrap().is_ok());
+        drop(db);
+
+        let db = DB::open(options).unwrap();
+        let tx = db.read_tx().unwrap();
+        let collection = tx.get_collection("small".to_string()).unwrap().unwrap();
+        assert!(collection.find("key".to_string(), &tx).unwrap().is_some());
+        tx.commit().unwrap();
+
+        // The lowest free page is handed out first.
+        let mut freelist = Freelist::new();
+        for _ in 0..5 {
+            freelist.get_next_page();
+        }
+        freelist.release_page(5);
+        freelist.release_page(2);
+        freelist.release_page(4);
+        assert_eq!(freelist.released_pages.iter().copied().collect::<Vec<u64>>(), vec![2, 4, 5]);
+        assert_eq!(freelist.get_next_page(), 2);
+        assert!(freelist.trim());
+        assert_eq!(freelist.max_page, 3);
+        assert!(freelist.released_pages.is_empty());
+        assert!(!freelist.trim());
+
+        // A page listed twice by a freelist read from the file is only handed out once, and kept for `check`.
+        let mut buf = [0u8; PAGE_SIZE];
+        for (i, value) in [5u64, 3, 2, 4, 2].iter().enumerate() {
+            buf[i * PAGE_ID_SIZE..(i + 1) * PAGE_ID_SIZE].copy_from_slice(&value.to_le_bytes());
+        }
+        let (freelist, next_page) = Freelist::deserialize(buf);
+        assert_eq!(next_page, None);
+        assert_eq!(freelist.released_pages.iter().copied().collect::<Vec<u64>>(), vec![2, 4]);
+        assert_eq!(freelist.duplicate_pages, vec![2]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "released twice")]
+    fn releasing_a_page_twice_panics() {
+        let mut freelist = Freelist::new();
+        let page_id = freelist.get_next_page();
+        freelist.release_page(page_id);
+        freelist.release_page(page_id);
+    }
+
+    #[test]
+    fn delete_collection_larger_than_a_freelist_page() {
+        let options = new_options("./db_tx_test_internal_11");
+
+        let db = DB::open(options.clone()).unwrap();
+        let fill = |tx: &mut TxMut| -> Result<(), CustomError> {
+            let mut collection = tx.create_collection("large".to_string())?;
+            for i in 0..15000 {
+                collection.put(format!("key_{:05}", i), vec![0u8; 200], tx)?;
+            }
+            Ok(())
+        };
+        db.update(fill).unwrap();
+        // A collection after it keeps the freed pages from being cut off the end of the file.
+        db.update(|tx| {
+            let mut collection = tx.create_collection("tail".to_string())?;
+            collection.put("key".to_string(), "value".as_bytes().to_owned(), tx)?;
+            Ok(())
+        }).unwrap();
+
+        db.update(|tx| tx.delete_collection("large".to_string())).unwrap();
+        let tx = db.read_tx().unwrap();
+        let max_page = tx.dal().freelist.as_ref().unwrap().max_page;
+        let released_pages = tx.dal().freelist.as_ref().unwrap().released_pages.clone();
+        let overflow_pages = tx.dal().freelist.as_ref().unwrap().overflow_pages.len();
+        assert!(released_pages.len() > 510);
+        assert!(overflow_pages > 0);
+        tx.commit().unwrap();
+        assert!(db.check().unwrap().is_ok());
+        drop(db);
+
+        let db = DB::open(options).unwrap();
+        let tx = db.read_tx().unwrap();
+        assert_eq!(tx.dal().freelist.as_ref().unwrap().released_pages, released_pages);
+        let collection = tx.get_collection("tail".to_string()).unwrap().unwrap();
+        assert!(collection.find("key".to_string(), &tx).unwrap().is_some());
+        tx.commit().unwrap();
+        assert!(db.check().unwrap().is_ok());
+
+        // The freed pages are reused, and the overflow pages given back once the freelist fits in one page. The
+        // overflow pages are still in use while the collection is written, so as many new pages may be taken.
+        db.update(fill).unwrap();
+        let tx = db.read_tx().unwrap();
+        assert!(tx.dal().freelist.as_ref().unwrap().max_page <= max_page + overflow_pages as u64);
+        assert!(tx.dal().freelist.as_ref().unwrap().overflow_pages.is_empty());
+        tx.commit().unwrap();
+        assert!(db.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn root_collection_split_is_committed() {
+        let options = new_options("./db_tx_test_internal_12");
+
+        let db = DB::open(options.clone()).unwrap();
+        db.update(|tx| {
+            for i in 0..100 {
+                tx.create_collection(format!("c_{:03}", i))?;
+            }
+            Ok(())
+        }).unwrap();
+        let root = db.read_tx().unwrap().dal().meta.as_ref().unwrap().root;
+        assert!(db.read_tx().unwrap().get_node(root).unwrap().is_leaf());
+
+        // Longer names split the root collection while its entries are updated, not created.
+        db.update(|tx| {
+            for i in 0..100 {
+                tx.rename_collection(format!("c_{:03}", i), format!("collection_with_a_much_longer_name_{:03}", i))?;
+            }
+            Ok(())
+        }).unwrap();
+        drop(db);
+
+        let db = DB::open(options).unwrap();
+        let tx = db.read_tx().unwrap();
+        let split_root = tx.dal().meta.as_ref().unwrap().root;
+        assert!(!tx.get_node(split_root).unwrap().is_leaf());
+        for i in 0..100 {
+            assert!(tx.get_collection(format!("collection_with_a_much_longer_name_{:03}", i)).unwrap().is_some());
+        }
+        tx.commit().unwrap();
+        assert!(db.check().unwrap().is_ok());
+    }
+}